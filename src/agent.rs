@@ -0,0 +1,266 @@
+use crate::crypto::{self, VaultKey};
+use crate::workspace;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, thread};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Idle timeout used when none is configured, in seconds
+pub const DEFAULT_TIMEOUT_SECS: u64 = 15 * 60;
+
+/// Environment variable overriding the default idle timeout
+pub const TIMEOUT_ENV: &str = "PRIVE_AGENT_TIMEOUT";
+
+/// How long a client waits for the agent to answer
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request sent from the CLI to the agent
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Encrypt { plaintext: Vec<u8> },
+    Decrypt { data: Vec<u8> },
+    Status,
+    Lock,
+}
+
+/// Response sent from the agent back to the CLI
+#[derive(Serialize, Deserialize)]
+struct Response {
+    data: Option<Vec<u8>>,
+    error: Option<String>,
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.as_mut() {
+            data.zeroize();
+        }
+    }
+}
+
+/// Returns the path of the Unix socket of the agent serving a vault
+///
+/// Every vault directory gets its own agent, so a key unlocked for one vault
+/// is never used to encrypt notes in another.
+pub fn socket_path(note_dir: &str) -> PathBuf {
+    let dir = fs::canonicalize(note_dir).unwrap_or_else(|_| PathBuf::from(note_dir));
+    let hash: String = Sha256::digest(dir.as_os_str().as_encoded_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    workspace::runtime_dir().join(format!("agent-{}.sock", hash))
+}
+
+/// Sends a single request to the agent serving a vault
+fn request(note_dir: &str, request: &Request) -> Result<Response, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket_path(note_dir))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut line = Zeroizing::new(serde_json::to_vec(request)?);
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut reply = Zeroizing::new(String::new());
+    BufReader::new(stream).read_line(&mut reply)?;
+    let mut response: Response = serde_json::from_str(&reply)?;
+    match response.error.take() {
+        Some(error) => Err(error.into()),
+        None => Ok(response),
+    }
+}
+
+/// Returns true if an agent is running and holds the vault's key
+pub fn is_running(note_dir: &str) -> bool {
+    request(note_dir, &Request::Status).is_ok()
+}
+
+/// Asks the agent to encrypt plaintext with the vault key
+pub fn encrypt(note_dir: &str, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut response = request(
+        note_dir,
+        &Request::Encrypt {
+            plaintext: plaintext.to_vec(),
+        },
+    )?;
+    response
+        .data
+        .take()
+        .ok_or_else(|| "Agent returned no data.".into())
+}
+
+/// Asks the agent to decrypt a note encrypted with the vault key
+pub fn decrypt(note_dir: &str, data: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let mut response = request(
+        note_dir,
+        &Request::Decrypt {
+            data: data.to_vec(),
+        },
+    )?;
+    response
+        .data
+        .take()
+        .map(Zeroizing::new)
+        .ok_or_else(|| "Agent returned no data.".into())
+}
+
+/// Tells the agent to forget the vault key and exit
+pub fn lock(note_dir: &str) -> Result<(), Box<dyn Error>> {
+    request(note_dir, &Request::Lock)?;
+    Ok(())
+}
+
+/// Starts a background agent holding the vault key
+///
+/// The key is handed to the new process over its stdin so it never
+/// appears on the command line or in the environment.
+pub fn start(
+    note_dir: &str,
+    vault_key: &VaultKey,
+    timeout_secs: u64,
+) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new(env::current_exe()?)
+        .args(["agent", "--serve", "--vault-dir", note_dir])
+        .args(["--timeout", &timeout_secs.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or("Failed to open the agent's stdin.")?
+        .write_all(vault_key.as_bytes())?;
+
+    for _ in 0..50 {
+        if is_running(note_dir) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Err("The agent did not start.".into())
+}
+
+/// Reads the vault key handed over by `start` and serves requests until idle or locked
+pub fn serve_from_stdin(note_dir: &str, timeout_secs: u64) -> Result<(), Box<dyn Error>> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    io::stdin().read_exact(bytes.as_mut())?;
+    let vault_key = Box::new(VaultKey::from_bytes(bytes.as_ref())?);
+    serve(
+        &socket_path(note_dir),
+        vault_key,
+        Duration::from_secs(timeout_secs),
+    )
+}
+
+/// Keeps the process out of core dumps and its key pages out of swap
+fn harden(vault_key: &VaultKey) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0);
+    }
+    let bytes = vault_key.as_bytes();
+    unsafe {
+        libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.len());
+    }
+}
+
+/// Serves encrypt and decrypt requests on the agent socket
+fn serve(path: &Path, vault_key: Box<VaultKey>, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    harden(&vault_key);
+
+    workspace::create_private_dir(&workspace::runtime_dir())?;
+    let _ = fs::remove_file(path);
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+
+    let mut last_used = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => match handle(stream, &vault_key, &mut last_used) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => eprintln!("Agent request failed: {}", e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if last_used.elapsed() >= timeout {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+            Err(e) => {
+                let _ = fs::remove_file(path);
+                return Err(e.into());
+            }
+        }
+    }
+
+    let _ = fs::remove_file(path);
+    let bytes = vault_key.as_bytes();
+    unsafe {
+        libc::munlock(bytes.as_ptr() as *const libc::c_void, bytes.len());
+    }
+    Ok(())
+}
+
+/// Handles one connection; returns true if the agent was asked to lock
+///
+/// Only encrypting and decrypting count as use, so polling the agent's
+/// status never keeps the key in memory past its idle timeout.
+fn handle(
+    stream: UnixStream,
+    vault_key: &VaultKey,
+    last_used: &mut Instant,
+) -> Result<bool, Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+
+    let mut line = Zeroizing::new(String::new());
+    BufReader::new(&stream).read_line(&mut line)?;
+    let request: Request = serde_json::from_str(&line)?;
+
+    let mut locked = false;
+    let result = match request {
+        Request::Encrypt { plaintext } => {
+            *last_used = Instant::now();
+            let plaintext = Zeroizing::new(plaintext);
+            crypto::encrypt_with_vault_key(&plaintext, vault_key).map(Some)
+        }
+        Request::Decrypt { data } => {
+            *last_used = Instant::now();
+            crypto::decrypt_with_vault_key(&data, vault_key)
+                .map(|plaintext| Some(plaintext.to_vec()))
+        }
+        Request::Status => Ok(None),
+        Request::Lock => {
+            locked = true;
+            Ok(None)
+        }
+    };
+
+    let response = match result {
+        Ok(data) => Response { data, error: None },
+        Err(e) => Response {
+            data: None,
+            error: Some(e.to_string()),
+        },
+    };
+    let mut reply = Zeroizing::new(serde_json::to_vec(&response)?);
+    reply.push(b'\n');
+    (&stream).write_all(&reply)?;
+    Ok(locked)
+}
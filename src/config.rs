@@ -0,0 +1,428 @@
+use crate::agent;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{value, DocumentMut, Item, Table};
+
+/// Environment variable naming a config file to use instead of the default one
+pub const CONFIG_ENV: &str = "PRIVE_CONFIG";
+
+/// Environment variable overriding the vault directory
+pub const VAULT_ENV: &str = "PRIVE_VAULT";
+
+/// Environment variable overriding the remote used by `prive init`
+pub const REMOTE_ENV: &str = "PRIVE_REMOTE";
+
+/// Environment variable overriding the branch used by `prive init`
+pub const BRANCH_ENV: &str = "PRIVE_BRANCH";
+
+/// Environment variable overriding the editor command
+pub const EDITOR_ENV: &str = "PRIVE_EDITOR";
+
+/// Vault directory under the home directory used when none is configured
+const DEFAULT_VAULT_DIR: &str = ".prive-note";
+
+/// Settings as written in `config.toml`; every key is optional
+///
+/// The top-level `vault`, `remote` and `branch` describe the unnamed vault used
+/// when no named vault is selected.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    vault: Option<String>,
+    remote: Option<String>,
+    branch: Option<String>,
+    editor: Option<String>,
+    agent_timeout: Option<u64>,
+    default_vault: Option<String>,
+    vaults: BTreeMap<String, VaultProfile>,
+}
+
+/// A named vault, written as a `[vaults.NAME]` table in `config.toml`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VaultProfile {
+    /// Directory holding the vault's git checkout
+    pub path: String,
+    /// Git URL `prive init` connects to when none is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Remote branch `prive init` syncs with when none is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Agent idle timeout for this vault, overriding the top-level one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_timeout: Option<u64>,
+}
+
+/// Settings for one run of prive, from the config file, the environment and the command line
+pub struct Config {
+    /// Name of the selected vault, or None for the unnamed vault
+    pub vault_name: Option<String>,
+    /// Directory holding the vault's git checkout
+    pub note_dir: String,
+    /// Git URL `prive init` connects to when none is given
+    pub remote: Option<String>,
+    /// Remote branch `prive init` syncs with when none is given
+    pub branch: Option<String>,
+    /// Editor command, tried before `$VISUAL` and `$EDITOR`
+    pub editor: Option<String>,
+    /// Seconds of inactivity before the agent forgets the vault key
+    pub agent_timeout: u64,
+}
+
+impl Config {
+    /// Loads the config file, selects a vault and applies the `PRIVE_*` environment overrides
+    ///
+    /// A file given with `--config` or `PRIVE_CONFIG` must exist; the default
+    /// `$XDG_CONFIG_HOME/prive/config.toml` is optional. The vault named by
+    /// `vault`, or else by `default_vault`, replaces the top-level vault settings.
+    /// A vault named with `--vault` is used as configured: `PRIVE_VAULT`,
+    /// `PRIVE_REMOTE` and `PRIVE_BRANCH` only override the default vault.
+    pub fn load(path: Option<&Path>, vault: Option<&str>) -> Result<Config, String> {
+        let mut file = load_file(path)?;
+        let vault_env = |name| {
+            if vault.is_some() {
+                None
+            } else {
+                env_value(name)
+            }
+        };
+
+        let vault_name = vault.map(str::to_string).or(file.default_vault.take());
+        if let Some(name) = &vault_name {
+            let profile = file.vaults.remove(name).ok_or_else(|| {
+                format!(
+                    "No vault named '{}' is configured; see `prive vault list`.",
+                    name
+                )
+            })?;
+            file.vault = Some(profile.path);
+            file.remote = profile.remote;
+            file.branch = profile.branch;
+            file.agent_timeout = profile.agent_timeout.or(file.agent_timeout);
+        }
+
+        let note_dir = match vault_env(VAULT_ENV).or(file.vault) {
+            Some(vault) => expand_home(&vault)?,
+            None => home_dir()
+                .ok_or_else(|| {
+                    format!(
+                        "HOME is not set; configure the vault directory with `vault` in {} or {}.",
+                        default_path()
+                            .map_or("config.toml".into(), |path| path.display().to_string()),
+                        VAULT_ENV
+                    )
+                })?
+                .join(DEFAULT_VAULT_DIR),
+        };
+        let agent_timeout = match env_value(agent::TIMEOUT_ENV) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("{} must be a number of seconds.", agent::TIMEOUT_ENV))?,
+            None => file.agent_timeout.unwrap_or(agent::DEFAULT_TIMEOUT_SECS),
+        };
+
+        Ok(Config {
+            vault_name,
+            note_dir: note_dir.to_string_lossy().into_owned(),
+            remote: vault_env(REMOTE_ENV).or(file.remote),
+            branch: vault_env(BRANCH_ENV).or(file.branch),
+            editor: env_value(EDITOR_ENV).or(file.editor),
+            agent_timeout,
+        })
+    }
+}
+
+/// Returns `$XDG_CONFIG_HOME/prive`, with `~/.config` as the fallback config home
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = env_value("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".config")))?;
+    Some(config_home.join("prive"))
+}
+
+/// Returns `$XDG_CONFIG_HOME/prive/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// Returns the config file to use: the one given, then `PRIVE_CONFIG`, then the default one
+pub fn file_path(path: Option<&Path>) -> Result<PathBuf, String> {
+    path.map(Path::to_path_buf)
+        .or_else(|| env_value(CONFIG_ENV).map(PathBuf::from))
+        .or_else(default_path)
+        .ok_or_else(|| {
+            format!(
+                "HOME is not set; give the config file with --config or {}.",
+                CONFIG_ENV
+            )
+        })
+}
+
+/// Reads the config file, treating a missing default file as empty
+fn load_file(path: Option<&Path>) -> Result<ConfigFile, String> {
+    let explicit = path
+        .map(Path::to_path_buf)
+        .or_else(|| env_value(CONFIG_ENV).map(PathBuf::from));
+    match (explicit, default_path()) {
+        (Some(path), _) => read_file(&path),
+        (None, Some(path)) if path.exists() => read_file(&path),
+        _ => Ok(ConfigFile::default()),
+    }
+}
+
+/// Reads and parses a config file
+fn read_file(path: &Path) -> Result<ConfigFile, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+/// Returns the named vaults in the config file and the name of the default one
+pub fn vaults(
+    path: Option<&Path>,
+) -> Result<(BTreeMap<String, VaultProfile>, Option<String>), String> {
+    let file = load_file(path)?;
+    Ok((file.vaults, file.default_vault))
+}
+
+/// Checks that a vault name can be used as a TOML key and on the command line
+pub fn validate_vault_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid vault name '{}'; use letters, digits, '-' and '_'.",
+            name
+        ))
+    }
+}
+
+/// Opens the config file for editing, keeping its comments and layout
+fn edit_file(path: &Path) -> Result<DocumentMut, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let document: DocumentMut = text
+        .parse()
+        .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+    // Refuse to rewrite a file prive itself would reject
+    toml::from_str::<ConfigFile>(&text)
+        .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+    Ok(document)
+}
+
+/// Writes an edited config file back, creating its directory if needed
+fn save_file(path: &Path, document: &DocumentMut) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, document.to_string())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Adds a `[vaults.NAME]` table to the config file
+pub fn add_vault(path: &Path, name: &str, profile: &VaultProfile) -> Result<(), String> {
+    validate_vault_name(name)?;
+    let mut document = edit_file(path)?;
+    let vaults = document
+        .entry("vaults")
+        .or_insert_with(|| {
+            let mut vaults = Table::new();
+            vaults.set_implicit(true);
+            Item::Table(vaults)
+        })
+        .as_table_mut()
+        .ok_or("`vaults` in the config file is not a table.")?;
+    if vaults.contains_key(name) {
+        return Err(format!("A vault named '{}' is already configured.", name));
+    }
+
+    let mut table = Table::new();
+    table["path"] = value(&profile.path);
+    if let Some(remote) = &profile.remote {
+        table["remote"] = value(remote);
+    }
+    if let Some(branch) = &profile.branch {
+        table["branch"] = value(branch);
+    }
+    if let Some(timeout) = profile.agent_timeout {
+        table["agent_timeout"] = value(timeout as i64);
+    }
+    vaults.insert(name, Item::Table(table));
+    save_file(path, &document)
+}
+
+/// Removes a named vault from the config file, and clears it as the default
+pub fn remove_vault(path: &Path, name: &str) -> Result<(), String> {
+    let mut document = edit_file(path)?;
+    let removed = document
+        .get_mut("vaults")
+        .and_then(Item::as_table_mut)
+        .and_then(|vaults| vaults.remove(name));
+    if removed.is_none() {
+        return Err(format!("No vault named '{}' is configured.", name));
+    }
+    if document.get("default_vault").and_then(Item::as_str) == Some(name) {
+        document.remove("default_vault");
+    }
+    save_file(path, &document)
+}
+
+/// Makes a named vault the default, or the unnamed vault when `name` is None
+pub fn set_default_vault(path: &Path, name: Option<&str>) -> Result<(), String> {
+    let mut document = edit_file(path)?;
+    match name {
+        Some(name) => {
+            let exists = document
+                .get("vaults")
+                .and_then(Item::as_table)
+                .is_some_and(|vaults| vaults.contains_key(name));
+            if !exists {
+                return Err(format!("No vault named '{}' is configured.", name));
+            }
+            document["default_vault"] = value(name);
+        }
+        None => {
+            document.remove("default_vault");
+        }
+    }
+    save_file(path, &document)
+}
+
+/// Returns an environment variable if it is set and not empty
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Returns the home directory, if HOME is set
+fn home_dir() -> Option<PathBuf> {
+    env_value("HOME").map(PathBuf::from)
+}
+
+/// Expands a leading `~/` in a configured path
+fn expand_home(path: &str) -> Result<PathBuf, String> {
+    match path.strip_prefix("~/") {
+        Some(rest) => home_dir()
+            .map(|home| home.join(rest))
+            .ok_or_else(|| format!("HOME is not set, so '{}' cannot be expanded.", path)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serialises the tests that read or change the `PRIVE_*` variables
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const CONFIG: &str = r#"
+vault = "/vaults/personal"
+remote = "git@example.com:personal.git"
+agent_timeout = 60
+default_vault = "work"
+
+[vaults.work]
+path = "/vaults/work"
+remote = "git@example.com:work.git"
+branch = "trunk"
+
+[vaults.family]
+path = "/vaults/family"
+agent_timeout = 30
+"#;
+
+    /// Writes `CONFIG` to a file of its own and loads it with the environment given
+    fn load_with_env(vault: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, String> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = env::temp_dir().join(format!(
+            "prive-config-test-{}-{}.toml",
+            std::process::id(),
+            vault.unwrap_or("default")
+        ));
+        fs::write(&path, CONFIG).unwrap();
+        for name in [VAULT_ENV, REMOTE_ENV, BRANCH_ENV, agent::TIMEOUT_ENV] {
+            env::remove_var(name);
+        }
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let config = Config::load(Some(&path), vault);
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn default_vault_replaces_top_level_settings() {
+        let config = load_with_env(None, &[]).unwrap();
+        assert_eq!(config.vault_name.as_deref(), Some("work"));
+        assert_eq!(config.note_dir, "/vaults/work");
+        assert_eq!(config.remote.as_deref(), Some("git@example.com:work.git"));
+        assert_eq!(config.branch.as_deref(), Some("trunk"));
+        assert_eq!(config.agent_timeout, 60);
+    }
+
+    #[test]
+    fn named_vault_overrides_the_default() {
+        let config = load_with_env(Some("family"), &[]).unwrap();
+        assert_eq!(config.vault_name.as_deref(), Some("family"));
+        assert_eq!(config.note_dir, "/vaults/family");
+        assert_eq!(config.remote, None);
+        assert_eq!(config.branch, None);
+        assert_eq!(config.agent_timeout, 30);
+
+        assert!(load_with_env(Some("missing"), &[]).is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_default_vault() {
+        let vars = [
+            (VAULT_ENV, "/vaults/env"),
+            (REMOTE_ENV, "git@example.com:env.git"),
+            (BRANCH_ENV, "env-branch"),
+            (agent::TIMEOUT_ENV, "5"),
+        ];
+        let config = load_with_env(None, &vars).unwrap();
+        assert_eq!(config.note_dir, "/vaults/env");
+        assert_eq!(config.remote.as_deref(), Some("git@example.com:env.git"));
+        assert_eq!(config.branch.as_deref(), Some("env-branch"));
+        assert_eq!(config.agent_timeout, 5);
+    }
+
+    #[test]
+    fn explicit_vault_wins_over_environment() {
+        let vars = [
+            (VAULT_ENV, "/vaults/env"),
+            (REMOTE_ENV, "git@example.com:env.git"),
+            (BRANCH_ENV, "env-branch"),
+        ];
+        let config = load_with_env(Some("work"), &vars).unwrap();
+        assert_eq!(config.note_dir, "/vaults/work");
+        assert_eq!(config.remote.as_deref(), Some("git@example.com:work.git"));
+        assert_eq!(config.branch.as_deref(), Some("trunk"));
+
+        let config = load_with_env(Some("family"), &vars).unwrap();
+        assert_eq!(config.note_dir, "/vaults/family");
+        assert_eq!(config.remote, None);
+    }
+
+    #[test]
+    fn invalid_timeout_is_rejected() {
+        assert!(load_with_env(None, &[(agent::TIMEOUT_ENV, "soon")]).is_err());
+    }
+}
@@ -0,0 +1,657 @@
+use crate::workspace;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{AeadCore, KeyInit, XChaCha20Poly1305, XNonce};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::process::Command;
+use zeroize::Zeroizing;
+
+/// Magic bytes at the start of every `.secured` file written by prive
+pub const MAGIC: &[u8; 5] = b"PRIVE";
+
+/// Current version of the `.secured` file format
+pub const VERSION: u8 = 3;
+
+/// Identifier of the Argon2id key derivation function in the file header
+const KDF_ARGON2ID: u8 = 1;
+
+/// Key slot holding password-derivation parameters
+const SLOT_PASSWORD: u8 = 1;
+/// Key slot holding a data key wrapped by the vault key
+const SLOT_VAULT: u8 = 2;
+
+/// Associated data bound to every wrapped data key
+const WRAP_AAD: &[u8] = b"prive data key";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const PREFIX_LEN: usize = MAGIC.len() + 1;
+const KDF_LEN: usize = 1 + 3 * 4;
+
+/// Highest memory cost, in KiB, accepted from a file header (4 GiB)
+const MAX_M_COST: u32 = 4 * 1024 * 1024;
+/// Highest number of passes accepted from a file header
+const MAX_T_COST: u32 = 64;
+/// Highest degree of parallelism accepted from a file header
+const MAX_P_COST: u32 = 16;
+
+/// Cost parameters for deriving a note key from its password
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    /// Number of passes over the memory
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// Parameters used for new notes
+    pub const DEFAULT: Self = KdfParams {
+        m_cost: 64 * 1024,
+        t_cost: 3,
+        p_cost: 1,
+    };
+
+    /// Parameters implied by version 1 files, which did not record them
+    const V1: Self = KdfParams {
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+    };
+
+    /// Returns true if every cost is at least as high as in `other`
+    pub fn at_least(&self, other: &KdfParams) -> bool {
+        self.m_cost >= other.m_cost && self.t_cost >= other.t_cost && self.p_cost >= other.p_cost
+    }
+
+    /// Checks the parameters are accepted by Argon2id and within the limits prive reads back
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.check_limits()?;
+        self.argon2()?;
+        Ok(())
+    }
+
+    /// Rejects costs a crafted file could use to exhaust memory or time
+    fn check_limits(&self) -> Result<(), Box<dyn Error>> {
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err(format!(
+                "Key derivation parameters ({}) exceed the supported limits (m={}KiB t={} p={}).",
+                self, MAX_M_COST, MAX_T_COST, MAX_P_COST
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, Box<dyn Error>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LEN))
+            .map_err(|e| format!("Invalid key derivation parameters: {}", e))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(KDF_LEN);
+        bytes.push(KDF_ARGON2ID);
+        bytes.extend_from_slice(&self.m_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.t_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.p_cost.to_le_bytes());
+        bytes
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.len() < KDF_LEN {
+            return Err("Encrypted file is truncated.".into());
+        }
+        if data[0] != KDF_ARGON2ID {
+            return Err(format!("Unknown key derivation function {}.", data[0]).into());
+        }
+        let read_u32 = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let kdf = KdfParams {
+            m_cost: read_u32(1),
+            t_cost: read_u32(5),
+            p_cost: read_u32(9),
+        };
+        kdf.check_limits()?;
+        Ok(kdf)
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams::DEFAULT
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "argon2id m={}KiB t={} p={}",
+            self.m_cost, self.t_cost, self.p_cost
+        )
+    }
+}
+
+/// Symmetric key that unlocks every note stored in vault mode
+pub struct VaultKey(Zeroizing<[u8; KEY_LEN]>);
+
+impl VaultKey {
+    /// Generates a new random vault key
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        OsRng.fill_bytes(key.as_mut());
+        VaultKey(key)
+    }
+
+    /// Restores a vault key from its raw bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let bytes: [u8; KEY_LEN] = bytes.try_into().map_err(|_| "Invalid vault key length.")?;
+        Ok(VaultKey(Zeroizing::new(bytes)))
+    }
+
+    /// Returns the raw key bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// How the key protecting a note is obtained
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMode {
+    /// The note has its own password
+    Password,
+    /// The note's data key is wrapped by the vault key
+    Vault,
+}
+
+/// Describes how the content key of a note is recovered
+enum KeySlot {
+    Password {
+        kdf: KdfParams,
+        salt: [u8; SALT_LEN],
+    },
+    Vault {
+        wrap_nonce: [u8; NONCE_LEN],
+        wrapped_key: Vec<u8>,
+    },
+}
+
+impl KeySlot {
+    fn mode(&self) -> KeyMode {
+        match self {
+            KeySlot::Password { .. } => KeyMode::Password,
+            KeySlot::Vault { .. } => KeyMode::Vault,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (slot_type, body) = match self {
+            KeySlot::Password { kdf, salt } => {
+                let mut body = kdf.to_bytes();
+                body.extend_from_slice(salt);
+                (SLOT_PASSWORD, body)
+            }
+            KeySlot::Vault {
+                wrap_nonce,
+                wrapped_key,
+            } => {
+                let mut body = wrap_nonce.to_vec();
+                body.extend_from_slice(wrapped_key);
+                (SLOT_VAULT, body)
+            }
+        };
+        let mut bytes = vec![slot_type];
+        bytes.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    fn parse(slot_type: u8, body: &[u8]) -> Result<Self, Box<dyn Error>> {
+        match slot_type {
+            SLOT_PASSWORD => {
+                if body.len() != KDF_LEN + SALT_LEN {
+                    return Err("Malformed password key slot.".into());
+                }
+                let mut salt = [0u8; SALT_LEN];
+                salt.copy_from_slice(&body[KDF_LEN..]);
+                Ok(KeySlot::Password {
+                    kdf: KdfParams::parse(body)?,
+                    salt,
+                })
+            }
+            SLOT_VAULT => {
+                if body.len() != NONCE_LEN + KEY_LEN + TAG_LEN {
+                    return Err("Malformed vault key slot.".into());
+                }
+                let mut wrap_nonce = [0u8; NONCE_LEN];
+                wrap_nonce.copy_from_slice(&body[..NONCE_LEN]);
+                Ok(KeySlot::Vault {
+                    wrap_nonce,
+                    wrapped_key: body[NONCE_LEN..].to_vec(),
+                })
+            }
+            _ => Err(format!("Unknown key slot type {}.", slot_type).into()),
+        }
+    }
+}
+
+/// Parsed header of a `.secured` file
+struct Header {
+    slot: KeySlot,
+    nonce: [u8; NONCE_LEN],
+    /// Number of bytes the header occupies at the start of the file
+    len: usize,
+}
+
+impl Header {
+    /// Serializes a current-version header; these bytes are also bound to the ciphertext as associated data
+    fn to_bytes(slot: &KeySlot, nonce: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&slot.to_bytes());
+        bytes.extend_from_slice(nonce);
+        bytes
+    }
+
+    /// Parses the header from the start of a `.secured` file
+    fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if !is_prive_format(data) {
+            return Err("Not a prive encrypted file.".into());
+        }
+        let truncated = || -> Box<dyn Error> { "Encrypted file is truncated.".into() };
+        let version = data[MAGIC.len()];
+        let mut pos = PREFIX_LEN;
+
+        let slot = match version {
+            1 | 2 => {
+                let kdf = if version == 1 {
+                    KdfParams::V1
+                } else {
+                    let kdf = KdfParams::parse(&data[pos..])?;
+                    pos += KDF_LEN;
+                    kdf
+                };
+                let salt_bytes = data.get(pos..pos + SALT_LEN).ok_or_else(truncated)?;
+                let mut salt = [0u8; SALT_LEN];
+                salt.copy_from_slice(salt_bytes);
+                pos += SALT_LEN;
+                KeySlot::Password { kdf, salt }
+            }
+            3 => {
+                let slot_prefix = data.get(pos..pos + 3).ok_or_else(truncated)?;
+                let body_len = u16::from_le_bytes([slot_prefix[1], slot_prefix[2]]) as usize;
+                let body = data
+                    .get(pos + 3..pos + 3 + body_len)
+                    .ok_or_else(truncated)?;
+                let slot = KeySlot::parse(slot_prefix[0], body)?;
+                pos += 3 + body_len;
+                slot
+            }
+            _ => return Err(format!("Unsupported file format version {}.", version).into()),
+        };
+
+        let nonce_bytes = data.get(pos..pos + NONCE_LEN).ok_or_else(truncated)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(nonce_bytes);
+
+        Ok(Header {
+            slot,
+            nonce,
+            len: pos + NONCE_LEN,
+        })
+    }
+}
+
+/// Returns true if the data starts with the prive file header
+pub fn is_prive_format(data: &[u8]) -> bool {
+    data.len() > MAGIC.len() && data.starts_with(MAGIC)
+}
+
+/// Returns how the key of a `.secured` file is obtained
+pub fn key_mode(data: &[u8]) -> Result<KeyMode, Box<dyn Error>> {
+    Ok(Header::parse(data)?.slot.mode())
+}
+
+/// Returns the key derivation parameters recorded in a password-protected `.secured` file
+pub fn kdf_params(data: &[u8]) -> Result<KdfParams, Box<dyn Error>> {
+    match Header::parse(data)?.slot {
+        KeySlot::Password { kdf, .. } => Ok(kdf),
+        KeySlot::Vault { .. } => Err("Note is encrypted with the vault key.".into()),
+    }
+}
+
+/// Derives the note key from a password and salt
+fn derive_key(
+    password: &str,
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> Result<Zeroizing<[u8; KEY_LEN]>, Box<dyn Error>> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    kdf.argon2()?
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypts plaintext under a key slot and content key, returning the full file contents
+fn seal(slot: &KeySlot, key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let header_bytes = Header::to_bytes(slot, &nonce);
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &header_bytes,
+            },
+        )
+        .map_err(|_| "Encryption failed.")?;
+
+    let mut output = header_bytes;
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+/// Decrypts the body of a file whose header has already been parsed
+fn open(header: &Header, key: &[u8], data: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(&header.nonce),
+            Payload {
+                msg: &data[header.len..],
+                aad: &data[..header.len],
+            },
+        )
+        .map_err(|_| "Incorrect password or corrupted note.")?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Encrypts plaintext with a password, returning the full `.secured` file contents
+pub fn encrypt(plaintext: &[u8], password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    encrypt_with_params(plaintext, password, &KdfParams::DEFAULT)
+}
+
+/// Encrypts plaintext with a password using the given key derivation costs
+pub fn encrypt_with_params(
+    plaintext: &[u8],
+    password: &str,
+    kdf: &KdfParams,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(password, &salt, kdf)?;
+    seal(
+        &KeySlot::Password { kdf: *kdf, salt },
+        key.as_ref(),
+        plaintext,
+    )
+}
+
+/// Decrypts the contents of a password-protected `.secured` file
+pub fn decrypt(data: &[u8], password: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let header = Header::parse(data)?;
+    match &header.slot {
+        KeySlot::Password { kdf, salt } => {
+            let key = derive_key(password, salt, kdf)?;
+            open(&header, key.as_ref(), data)
+        }
+        KeySlot::Vault { .. } => Err("Note is encrypted with the vault key.".into()),
+    }
+}
+
+/// Encrypts plaintext under a fresh data key wrapped by the vault key
+pub fn encrypt_with_vault_key(
+    plaintext: &[u8],
+    vault_key: &VaultKey,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let data_key = VaultKey::generate();
+    let wrap_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let wrapped_key = XChaCha20Poly1305::new(vault_key.as_bytes().into())
+        .encrypt(
+            &wrap_nonce,
+            Payload {
+                msg: data_key.as_bytes(),
+                aad: WRAP_AAD,
+            },
+        )
+        .map_err(|_| "Failed to wrap the data key.")?;
+
+    let slot = KeySlot::Vault {
+        wrap_nonce: wrap_nonce.into(),
+        wrapped_key,
+    };
+    seal(&slot, data_key.as_bytes(), plaintext)
+}
+
+/// Decrypts a `.secured` file whose data key is wrapped by the vault key
+pub fn decrypt_with_vault_key(
+    data: &[u8],
+    vault_key: &VaultKey,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let header = Header::parse(data)?;
+    match &header.slot {
+        KeySlot::Vault {
+            wrap_nonce,
+            wrapped_key,
+        } => {
+            let data_key = XChaCha20Poly1305::new(vault_key.as_bytes().into())
+                .decrypt(
+                    XNonce::from_slice(wrap_nonce),
+                    Payload {
+                        msg: wrapped_key,
+                        aad: WRAP_AAD,
+                    },
+                )
+                .map_err(|_| "Note was not encrypted with this vault key.")?;
+            let data_key = Zeroizing::new(data_key);
+            open(&header, &data_key, data)
+        }
+        KeySlot::Password { .. } => Err("Note is protected by its own password.".into()),
+    }
+}
+
+/// Decrypts a file produced by the old `secured` binary.
+///
+/// The binary is only needed for notes that have not been saved since upgrading;
+/// it prompts for the password itself and writes the plaintext next to a private
+/// copy of the ciphertext, in a fresh 0700 directory under the runtime directory.
+/// The plaintext is read back and wiped.
+pub fn decrypt_legacy_file(path: &Path) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let runtime_dir = workspace::runtime_dir();
+    workspace::create_private_dir(&runtime_dir)?;
+    let work_dir = runtime_dir.join(format!("legacy-{}", std::process::id()));
+    // Never reuse a directory someone else may have prepared
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&work_dir)
+        .map_err(|e| format!("Failed to create {}: {}", work_dir.display(), e))?;
+    let copy_path = work_dir.join("note.secured");
+    let plain_path = work_dir.join("note");
+
+    let result = fs::copy(path, &copy_path)
+        .map_err(|e| e.to_string())
+        .and_then(|_| {
+            Command::new("secured")
+                .arg("decrypt")
+                .arg(&copy_path)
+                .status()
+                .map_err(|e| {
+                    format!(
+                        "The legacy `secured` binary is required to read this note: {}",
+                        e
+                    )
+                })
+        })
+        .and_then(|status| {
+            if status.success() {
+                Ok(())
+            } else {
+                Err("Legacy decryption failed.".to_string())
+            }
+        })
+        .and_then(|_| fs::read(&plain_path).map_err(|e| e.to_string()))
+        .map(Zeroizing::new);
+
+    if plain_path.exists() {
+        let _ = workspace::wipe_file(&plain_path);
+    }
+    let _ = fs::remove_dir_all(&work_dir);
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap costs so the tests do not spend seconds in Argon2
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    /// Builds a password-protected file in the version 1 or 2 layout
+    fn legacy_file(version: u8, kdf: &KdfParams, password: &str, plaintext: &[u8]) -> Vec<u8> {
+        let salt = [7u8; SALT_LEN];
+        let nonce = [9u8; NONCE_LEN];
+        let mut header = MAGIC.to_vec();
+        header.push(version);
+        if version == 2 {
+            header.extend_from_slice(&kdf.to_bytes());
+        }
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&nonce);
+
+        let key = derive_key(password, &salt, kdf).unwrap();
+        let ciphertext = XChaCha20Poly1305::new(key.as_ref().into())
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &header,
+                },
+            )
+            .unwrap();
+        header.extend_from_slice(&ciphertext);
+        header
+    }
+
+    #[test]
+    fn password_round_trip() {
+        let data = encrypt_with_params(b"secret note", "hunter2", &TEST_KDF).unwrap();
+        assert_eq!(key_mode(&data).unwrap(), KeyMode::Password);
+        assert_eq!(kdf_params(&data).unwrap(), TEST_KDF);
+        assert_eq!(
+            decrypt(&data, "hunter2").unwrap().as_slice(),
+            b"secret note"
+        );
+        assert!(decrypt(&data, "hunter3").is_err());
+    }
+
+    #[test]
+    fn vault_key_round_trip() {
+        let vault_key = VaultKey::generate();
+        let data = encrypt_with_vault_key(b"shared note", &vault_key).unwrap();
+        assert_eq!(key_mode(&data).unwrap(), KeyMode::Vault);
+        assert_eq!(
+            decrypt_with_vault_key(&data, &vault_key)
+                .unwrap()
+                .as_slice(),
+            b"shared note"
+        );
+        assert!(decrypt_with_vault_key(&data, &VaultKey::generate()).is_err());
+        assert!(decrypt(&data, "hunter2").is_err());
+        assert!(kdf_params(&data).is_err());
+    }
+
+    #[test]
+    fn wrong_key_mode_is_rejected() {
+        let data = encrypt_with_params(b"secret note", "hunter2", &TEST_KDF).unwrap();
+        assert!(decrypt_with_vault_key(&data, &VaultKey::generate()).is_err());
+    }
+
+    #[test]
+    fn reads_version_2_files() {
+        let data = legacy_file(2, &TEST_KDF, "hunter2", b"old note");
+        assert_eq!(kdf_params(&data).unwrap(), TEST_KDF);
+        assert_eq!(decrypt(&data, "hunter2").unwrap().as_slice(), b"old note");
+        assert!(decrypt(&data, "hunter3").is_err());
+    }
+
+    #[test]
+    fn reads_version_1_files() {
+        let data = legacy_file(1, &KdfParams::V1, "hunter2", b"oldest note");
+        assert_eq!(kdf_params(&data).unwrap(), KdfParams::V1);
+        assert_eq!(
+            decrypt(&data, "hunter2").unwrap().as_slice(),
+            b"oldest note"
+        );
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        let vault_data = encrypt_with_vault_key(b"note", &VaultKey::generate()).unwrap();
+        let password_data = encrypt_with_params(b"note", "hunter2", &TEST_KDF).unwrap();
+        let v1_data = legacy_file(1, &TEST_KDF, "hunter2", b"note");
+        let v2_data = legacy_file(2, &TEST_KDF, "hunter2", b"note");
+        for data in [&vault_data, &password_data, &v1_data, &v2_data] {
+            let header_len = Header::parse(data).unwrap().len;
+            for len in 0..header_len {
+                assert!(Header::parse(&data[..len]).is_err(), "length {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_files_fail_to_decrypt() {
+        let vault_key = VaultKey::generate();
+        let vault_data = encrypt_with_vault_key(b"shared note", &vault_key).unwrap();
+        for at in 0..vault_data.len() {
+            let mut tampered = vault_data.clone();
+            tampered[at] ^= 1;
+            assert!(
+                decrypt_with_vault_key(&tampered, &vault_key).is_err(),
+                "byte {}",
+                at
+            );
+        }
+
+        let password_data = encrypt_with_params(b"secret note", "hunter2", &TEST_KDF).unwrap();
+        for at in 0..password_data.len() {
+            let mut tampered = password_data.clone();
+            tampered[at] ^= 1;
+            assert!(decrypt(&tampered, "hunter2").is_err(), "byte {}", at);
+        }
+
+        let truncated = &vault_data[..vault_data.len() - 1];
+        assert!(decrypt_with_vault_key(truncated, &vault_key).is_err());
+    }
+
+    #[test]
+    fn excessive_kdf_costs_are_rejected() {
+        let mut data = legacy_file(2, &TEST_KDF, "hunter2", b"note");
+        let m_cost_at = PREFIX_LEN + 1;
+        data[m_cost_at..m_cost_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Header::parse(&data).is_err());
+        assert!(KdfParams {
+            m_cost: MAX_M_COST + 1,
+            ..TEST_KDF
+        }
+        .validate()
+        .is_err());
+        assert!(KdfParams::DEFAULT.validate().is_ok());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::Config;
+use crate::vault;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// Git config key naming the editor command, e.g. `code --wait {file}`
+///
+/// Still honoured for vaults set up before the config file; `editor` in the
+/// config file takes precedence.
+pub const EDITOR_CONFIG: &str = "prive.editor";
+
+/// Placeholder in an editor command replaced by the file to edit
+const FILE_PLACEHOLDER: &str = "{file}";
+
+/// Editor used when nothing is configured
+const DEFAULT_EDITOR: &str = "nvim";
+
+/// Options that keep Vim from writing swap, backup, undo or viminfo files
+const VIM_SETTINGS: &str = "set noswapfile nobackup nowritebackup noundofile viminfo=";
+
+/// Options that keep Neovim from writing swap, backup, undo or shada files
+const NVIM_SETTINGS: &str = "set noswapfile nobackup nowritebackup noundofile shada=";
+
+/// Emacs settings that disable backup, auto-save and lock files
+const EMACS_SETTINGS: &str =
+    "(setq make-backup-files nil auto-save-default nil create-lockfiles nil)";
+
+/// Returns the editor command from the config, then `prive.editor`, then `$VISUAL`, then `$EDITOR`
+pub fn command(config: &Config) -> String {
+    config
+        .editor
+        .clone()
+        .or_else(|| {
+            vault::Repo::open(&config.note_dir)
+                .ok()
+                .and_then(|repo| repo.editor())
+        })
+        .or_else(|| env::var("VISUAL").ok())
+        .or_else(|| env::var("EDITOR").ok())
+        .filter(|command| !command.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string())
+}
+
+/// Splits a command into words, honouring single and double quotes
+fn split_words(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("Unclosed quote in editor command '{}'.", command));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Returns flags that stop an editor from copying the plaintext into swap, backup or undo files
+fn hardening_flags(program: &str) -> Vec<&'static str> {
+    let name = Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(program);
+    match name {
+        "vi" | "vim" | "gvim" | "mvim" => vec!["-n", "-i", "NONE", "--cmd", VIM_SETTINGS],
+        "nvim" => vec!["-n", "-i", "NONE", "--cmd", NVIM_SETTINGS],
+        "emacs" => vec!["--eval", EMACS_SETTINGS],
+        "micro" => vec![
+            "-backup",
+            "false",
+            "-savecursor",
+            "false",
+            "-saveundo",
+            "false",
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Opens a file in the configured editor and waits for it to exit
+///
+/// `{file}` in the command is replaced by the file's path; without it the path
+/// is passed as the last argument.
+pub fn edit(config: &Config, file: &Path) -> Result<(), Box<dyn Error>> {
+    let command = command(config);
+    let mut words = split_words(&command)?.into_iter();
+    let program = words
+        .next()
+        .ok_or_else(|| format!("The editor command '{}' is empty.", command))?;
+
+    let mut args: Vec<OsString> = hardening_flags(&program)
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+    let mut has_file = false;
+    for word in words {
+        if word == FILE_PLACEHOLDER {
+            args.push(file.as_os_str().to_owned());
+            has_file = true;
+        } else if word.contains(FILE_PLACEHOLDER) {
+            args.push(
+                word.replace(FILE_PLACEHOLDER, &file.to_string_lossy())
+                    .into(),
+            );
+            has_file = true;
+        } else {
+            args.push(word.into());
+        }
+    }
+    if !has_file {
+        args.push(file.as_os_str().to_owned());
+    }
+
+    let status = Command::new(&program)
+        .args(&args)
+        .status()
+        .map_err(|e| format!("Failed to start the editor '{}': {}", program, e))?;
+    if !status.success() {
+        return Err(format!("The editor '{}' exited with an error.", program).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_words_and_honours_quotes() {
+        assert_eq!(split_words("vim").unwrap(), ["vim"]);
+        assert_eq!(
+            split_words("  code --wait   {file} ").unwrap(),
+            ["code", "--wait", "{file}"]
+        );
+        assert_eq!(
+            split_words(r#""/Applications/My Editor" -n 'two words'"#).unwrap(),
+            ["/Applications/My Editor", "-n", "two words"]
+        );
+        assert_eq!(
+            split_words(r#"say "it's" ''"#).unwrap(),
+            ["say", "it's", ""]
+        );
+        assert_eq!(split_words(r#"pre"fix"ed"#).unwrap(), ["prefixed"]);
+        assert!(split_words("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unclosed_quotes_are_rejected() {
+        assert!(split_words("vim 'notes").is_err());
+        assert!(split_words("\"vim").is_err());
+    }
+
+    #[test]
+    fn hardening_flags_match_the_editor_binary() {
+        assert!(hardening_flags("/usr/bin/vim").contains(&"-n"));
+        assert!(hardening_flags("nvim").contains(&NVIM_SETTINGS));
+        assert!(hardening_flags("emacs").contains(&EMACS_SETTINGS));
+        assert!(hardening_flags("nano").is_empty());
+        assert!(hardening_flags("vimdiff").is_empty());
+    }
+}
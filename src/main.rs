@@ -1,23 +1,27 @@
-use clap::Parser;
+mod agent;
+mod config;
+mod crypto;
+mod editor;
+mod output;
+mod prompt;
+mod vault;
+mod workspace;
+
+use clap::{Parser, Subcommand};
+use config::Config;
 use rustyline::DefaultEditor;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::env::args;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::{env, io};
 
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::time::Duration;
-
-
-
 
 /// Command-line arguments for the program
 #[derive(Parser, Debug)]
@@ -25,6 +29,241 @@ use std::time::Duration;
 struct Args {
     #[arg(short, long)]
     open: Option<String>,
+
+    /// Print listings, history, status and their errors as versioned JSON documents
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Config file to use instead of $XDG_CONFIG_HOME/prive/config.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Named vault to use instead of the default one
+    #[arg(long, global = true, value_name = "NAME")]
+    vault: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+/// Subcommands that run without the interactive menu
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Create a note protected by the vault key
+    New {
+        /// Name of the new note
+        note: String,
+        /// Protect the note with its own password instead of the vault key
+        #[arg(long)]
+        separate: bool,
+        /// Password hint to store with a separately protected note
+        #[arg(long, requires = "separate")]
+        hint: Option<String>,
+    },
+    /// List the names of all notes
+    Ls,
+    /// Show whether the vault is set up, its remote and commits waiting to be synced
+    Status,
+    /// Open a note in the editor and save it when the editor exits
+    Edit {
+        /// Name of the note
+        note: String,
+    },
+    /// Print a decrypted note
+    Cat {
+        /// Name of the note
+        note: String,
+    },
+    /// Replace a note's content with stdin, creating the note if needed
+    Write {
+        /// Name of the note
+        note: String,
+    },
+    /// Append stdin to a note, creating the note if needed
+    Append {
+        /// Name of the note
+        note: String,
+    },
+    /// Delete a note
+    Rm {
+        /// Name of the note
+        note: String,
+    },
+    /// Rename a note
+    Mv {
+        /// Current name of the note
+        from: String,
+        /// New name of the note
+        to: String,
+    },
+    /// Show a note's password hint, or set it
+    Hint {
+        /// Name of the note
+        note: String,
+        /// New password hint
+        hint: Option<String>,
+    },
+    /// Re-encrypt notes with higher key derivation costs
+    Rekdf {
+        /// Argon2id memory cost in KiB
+        #[arg(long, default_value_t = crypto::KdfParams::DEFAULT.m_cost)]
+        memory: u32,
+        /// Argon2id number of passes
+        #[arg(long, default_value_t = crypto::KdfParams::DEFAULT.t_cost)]
+        iterations: u32,
+        /// Argon2id degree of parallelism
+        #[arg(long, default_value_t = crypto::KdfParams::DEFAULT.p_cost)]
+        parallelism: u32,
+        /// Re-encrypt notes that already use at least these costs
+        #[arg(long)]
+        force: bool,
+        /// Notes to re-encrypt; all notes when omitted
+        notes: Vec<String>,
+    },
+    /// Unlock the vault and keep its key in a background agent
+    Agent {
+        /// Seconds of inactivity before the agent forgets the key
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Serve requests with the key read from stdin (used internally)
+        #[arg(long, hide = true)]
+        serve: bool,
+        /// Vault directory the served key belongs to (used internally)
+        #[arg(long, hide = true, requires = "serve")]
+        vault_dir: Option<String>,
+    },
+    /// Make the agent forget the vault key
+    Lock,
+    /// Set up the vault and connect it to a git remote
+    Init {
+        /// Git URL of the remote to sync with (SSH, HTTPS or file://); defaults to `remote` from the config
+        #[arg(long)]
+        remote: Option<String>,
+        /// Create a private GitHub repository with the gh CLI instead
+        #[arg(long, conflicts_with = "remote")]
+        github: bool,
+        /// Remote branch to sync with instead of the remote's default branch
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// List the commits that changed a note
+    History {
+        /// Name of the note
+        note: String,
+    },
+    /// Print a note as it was at an earlier revision
+    Show {
+        /// Note and revision written as NOTE@REV, e.g. todo@HEAD~2
+        spec: String,
+    },
+    /// Pull changes from the remote and push commits made while offline
+    Sync,
+    /// List the device keys trusted to sign vault commits, or trust a new one
+    Trust {
+        /// Name of the device to trust
+        device: Option<String>,
+        /// SSH public key file or GPG key id; defaults to this device's signing key
+        #[arg(requires = "device")]
+        key: Option<String>,
+    },
+    /// List the members of a shared vault, or add and remove them
+    Member {
+        #[command(subcommand)]
+        command: MemberCommands,
+    },
+    /// Show or apply which members can read the notes in each folder of a shared vault
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommands,
+    },
+    /// Print a secured file decrypted for git diff (textconv driver)
+    GitTextconv {
+        /// File git asks to convert
+        #[arg(required_unless_present = "install")]
+        file: Option<String>,
+        /// Register the driver in the vault's .gitattributes and git config
+        #[arg(long)]
+        install: bool,
+    },
+    /// Make an earlier revision of a note the current one
+    Restore {
+        /// Name of the note
+        note: String,
+        /// Commit id or revision to restore, as listed by `prive history`
+        rev: String,
+    },
+    /// List, add and remove named vaults, or choose the default one
+    Vault {
+        #[command(subcommand)]
+        command: VaultCommands,
+    },
+}
+
+/// Subcommands of `prive member`, which manage who can open the vault key
+#[derive(Subcommand, Debug)]
+enum MemberCommands {
+    /// List the members and their age recipients
+    List,
+    /// Let a member open the vault key with their age identity
+    Add {
+        /// Name of the member
+        name: String,
+        /// age X25519 recipient (age1...); defaults to this device's member key, created if needed
+        recipient: Option<String>,
+    },
+    /// Remove a member and rotate the vault key so they cannot open new changes
+    Remove {
+        /// Name of the member
+        name: String,
+    },
+    /// Print this device's age recipient to hand to a member, creating the member key if needed
+    Key,
+}
+
+/// Subcommands of `prive policy`, which limit folders of a shared vault to some members
+#[derive(Subcommand, Debug)]
+enum PolicyCommands {
+    /// List each folder with its readers and whether the policy file is applied
+    Show,
+    /// Wrap folder keys for the readers in vault.policy and re-encrypt the notes to match
+    Apply,
+}
+
+/// Subcommands of `prive vault`, which edit the config file
+#[derive(Subcommand, Debug)]
+enum VaultCommands {
+    /// List the named vaults
+    List,
+    /// Add a named vault; set it up afterwards with `prive --vault NAME init`
+    Add {
+        /// Name of the vault
+        name: String,
+        /// Directory for the vault's git checkout; defaults to ~/.prive-note-NAME
+        #[arg(long)]
+        path: Option<String>,
+        /// Git URL of the remote to sync with
+        #[arg(long)]
+        remote: Option<String>,
+        /// Remote branch to sync with
+        #[arg(long)]
+        branch: Option<String>,
+        /// Seconds of inactivity before this vault's agent forgets the key
+        #[arg(long)]
+        agent_timeout: Option<u64>,
+    },
+    /// Remove a named vault from the config file, leaving its directory in place
+    Remove {
+        /// Name of the vault
+        name: String,
+    },
+    /// Show the default vault, or make a named vault the default
+    Default {
+        /// Name of the vault
+        name: Option<String>,
+        /// Make the unnamed vault from the top of the config file the default again
+        #[arg(long, conflicts_with = "name")]
+        clear: bool,
+    },
 }
 
 /// Struct to represent the login state
@@ -35,8 +274,8 @@ struct LoginState {
 
 impl LoginState {
     /// Loads the login state from a configuration file
-    fn load() -> Self {
-        let config_dir = format!("{}/.prive-note", env::var("HOME").unwrap());
+    fn load(config: &Config) -> Self {
+        let config_dir = &config.note_dir;
         let config_file = format!("{}/login_state.json", config_dir);
 
         if let Ok(mut file) = File::open(&config_file) {
@@ -49,12 +288,12 @@ impl LoginState {
     }
 
     /// Saves the login state to a configuration file
-    fn save(&self) {
-        let config_dir = format!("{}/.prive-note", env::var("HOME").unwrap());
+    fn save(&self, config: &Config) {
+        let config_dir = &config.note_dir;
         let config_file = format!("{}/login_state.json", config_dir);
 
         // Create the directory if it doesn't exist
-        fs::create_dir_all(&config_dir).unwrap_or_else(|_| ());
+        fs::create_dir_all(config_dir).unwrap_or(());
 
         let json = serde_json::to_string(self).unwrap();
         let mut file = File::create(&config_file).unwrap();
@@ -69,8 +308,8 @@ struct NoteDatabase {
 
 impl NoteDatabase {
     /// Loads the note database from a configuration file
-    fn load() -> Self {
-        let db_file = format!("{}/.prive-note/note-db.json", env::var("HOME").unwrap());
+    fn load(config: &Config) -> Self {
+        let db_file = format!("{}/note-db.json", config.note_dir);
 
         if let Ok(mut file) = File::open(&db_file) {
             let mut contents = String::new();
@@ -86,8 +325,8 @@ impl NoteDatabase {
     }
 
     /// Saves the note database to a configuration file
-    fn save(&self) {
-        let db_file = format!("{}/.prive-note/note-db.json", env::var("HOME").unwrap());
+    fn save(&self, config: &Config) {
+        let db_file = format!("{}/note-db.json", config.note_dir);
 
         let json = serde_json::to_string(self).unwrap();
         let mut file = File::create(&db_file).unwrap();
@@ -101,7 +340,6 @@ impl NoteDatabase {
 
     fn get_password_hint_with_default(&self, file: &str) -> String {
         self.get_password_hint(file)
-            .map(String::from)
             .unwrap_or_else(|| "No hint".to_string())
     }
 
@@ -112,55 +350,171 @@ impl NoteDatabase {
 
 fn main() {
     let args = Args::parse();
-
-    if let Some(note) = args.open {
-        open_note(&note);
-    } else {
-        let mut login_state = LoginState::load();
-
-        // Check if the user is already logged in to GitHub
-        if !login_state.logged_in {
-            println!("Are you already logged in to GitHub? (yes/no)");
-            let mut choice = String::new();
-            if let Ok(_) = io::stdin().read_line(&mut choice) {
-                match choice.trim().to_lowercase().as_str() {
-                    "yes" => {
-                        handle_repository();
-                        login_state.logged_in = true;
-                        login_state.save();
-                    }
-                    "no" => {
-                        if login() {
-                            println!("Login successful. Proceeding to handle repositories...");
-                            handle_repository();
-                            login_state.logged_in = true; // Set logged_in to true after successful login
-                            login_state.save(); // Save the login state
-                        } else {
-                            println!("Login failed.");
-                            return;
-                        }
+    if args.json {
+        output::enable_json();
+    }
+    // Managing vaults must keep working when the selected vault is misconfigured
+    if let Some(Commands::Vault { command }) = args.command {
+        exit_on_failure(manage_vaults(args.config.as_deref(), command));
+        return;
+    }
+    let config = match Config::load(args.config.as_deref(), args.vault.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let config = &config;
+
+    if let Some(command) = args.command {
+        match command {
+            Commands::New {
+                note,
+                separate,
+                hint,
+            } => exit_on_failure(new_note(config, &note, separate, hint)),
+            Commands::Ls => exit_on_failure(print_note_names(config)),
+            Commands::Status => exit_on_failure(show_status(config)),
+            Commands::Edit { note } => exit_on_failure(edit_note(config, &note)),
+            Commands::Cat { note } => exit_on_failure(cat_note(config, &note)),
+            Commands::Write { note } => {
+                exit_on_failure(write_note_from_stdin(config, &note, false))
+            }
+            Commands::Append { note } => {
+                exit_on_failure(write_note_from_stdin(config, &note, true))
+            }
+            Commands::Rm { note } => exit_on_failure(rm_note(config, &note)),
+            Commands::Mv { from, to } => exit_on_failure(move_note(config, &from, &to)),
+            Commands::Hint { note, hint } => exit_on_failure(note_hint(config, &note, hint)),
+            Commands::Rekdf {
+                memory,
+                iterations,
+                parallelism,
+                force,
+                notes,
+            } => {
+                let target = crypto::KdfParams {
+                    m_cost: memory,
+                    t_cost: iterations,
+                    p_cost: parallelism,
+                };
+                exit_on_failure(rekdf_notes(config, &notes, &target, force));
+            }
+            Commands::Agent {
+                timeout,
+                serve,
+                vault_dir,
+            } => {
+                let timeout = timeout.unwrap_or(config.agent_timeout);
+                if serve {
+                    let note_dir = vault_dir.as_deref().unwrap_or(&config.note_dir);
+                    if let Err(e) = agent::serve_from_stdin(note_dir, timeout) {
+                        eprintln!("Agent stopped: {}", e);
+                        process::exit(1);
                     }
+                } else {
+                    exit_on_failure(start_agent(config, timeout));
+                }
+            }
+            Commands::Lock => exit_on_failure(match agent::lock(&config.note_dir) {
+                Ok(_) => {
+                    println!("Vault locked.");
+                    true
+                }
+                Err(_) => {
+                    eprintln!("No agent is running.");
+                    false
+                }
+            }),
+            Commands::Init {
+                remote,
+                github,
+                branch,
+            } => {
+                let branch = branch.or_else(|| config.branch.clone());
+                let initialised = match remote.or_else(|| config.remote.clone()) {
+                    Some(url) if !github => init_repository(config, &url, branch.as_deref()),
+                    _ if github => create_github_repository(config, branch.as_deref()),
                     _ => {
-                        println!("Invalid choice. Exiting...");
-                        return;
+                        eprintln!("Give --remote or --github, or set `remote` in the config file.");
+                        false
                     }
+                };
+                if initialised {
+                    let mut login_state = LoginState::load(config);
+                    login_state.logged_in = true;
+                    login_state.save(config);
                 }
-            } else {
-                println!("Error reading input.");
+                exit_on_failure(initialised);
+            }
+            Commands::History { note } => exit_on_failure(show_note_history(config, &note)),
+            Commands::Show { spec } => exit_on_failure(match spec.rsplit_once('@') {
+                Some((note, rev)) => show_note_revision(config, note, rev),
+                None => {
+                    eprintln!("Expected NOTE@REV, for example {}@HEAD~1.", spec);
+                    false
+                }
+            }),
+            Commands::Restore { note, rev } => exit_on_failure(restore_note(config, &note, &rev)),
+            Commands::Sync => exit_on_failure(sync_vault(config)),
+            Commands::Trust { device, key } => match device {
+                Some(device) => exit_on_failure(trust_device(config, &device, key.as_deref())),
+                None => exit_on_failure(list_trusted_devices(config)),
+            },
+            Commands::Member { command } => exit_on_failure(match command {
+                MemberCommands::List => list_members(config),
+                MemberCommands::Add { name, recipient } => {
+                    add_member(config, &name, recipient.as_deref())
+                }
+                MemberCommands::Remove { name } => remove_member(config, &name),
+                MemberCommands::Key => show_member_key(),
+            }),
+            Commands::Policy { command } => exit_on_failure(match command {
+                PolicyCommands::Show => show_policy(config),
+                PolicyCommands::Apply => apply_policy(config),
+            }),
+            Commands::GitTextconv { file, install } => match file {
+                Some(file) if !install => exit_on_failure(git_textconv(config, &file)),
+                _ => exit_on_failure(install_textconv(config)),
+            },
+            Commands::Vault { .. } => unreachable!("handled before the config is loaded"),
+        }
+    } else if let Some(note) = args.open {
+        recover_orphaned_notes(config);
+        open_note(config, &note);
+    } else {
+        let mut login_state = LoginState::load(config);
+
+        // Check if the vault has been set up on this device
+        if !login_state.logged_in {
+            if !setup_vault(config) {
                 return;
             }
+            login_state.logged_in = true;
+            login_state.save(config);
         }
 
-        run_interactive_menu();
+        recover_orphaned_notes(config);
+        run_interactive_menu(config);
     }
 }
 
 /// Runs the interactive menu for the CLI application
-fn run_interactive_menu() {
+fn run_interactive_menu(config: &Config) {
     let mut rl = DefaultEditor::new().unwrap();
-    let mut login_state = LoginState::load();
+    let mut login_state = LoginState::load(config);
 
     loop {
+        // Commits made while offline wait here until they are synced
+        if login_state.logged_in {
+            if let Ok(count @ 1..) =
+                vault::Repo::open(&config.note_dir).and_then(|repo| repo.unsynced_commits())
+            {
+                println!("{} commit(s) not synced.", count);
+            }
+        }
+
         println!("Choose an option:");
         if !login_state.logged_in {
             // Display login options if not logged in
@@ -172,23 +526,25 @@ fn run_interactive_menu() {
             println!("3. Delete Note");
         }
         println!("4. Exit");
+        if login_state.logged_in {
+            println!("5. Sync");
+        }
 
         if let Ok(line) = rl.readline("> ") {
             match line.trim().parse::<u32>() {
                 Ok(choice) => match choice {
                     1 if !login_state.logged_in => {
-                        if login() {
-                            println!("Login successful. Proceeding to handle repositories...");
-                            handle_repository();
-                            login_state.logged_in = true; // Set logged_in to true after successful login
-                            login_state.save(); // Save the login state
-                        } else {
-                            println!("Login failed.");
+                        if setup_vault(config) {
+                            login_state.logged_in = true;
+                            login_state.save(config);
                         }
                     }
-                    1 if login_state.logged_in => create_note(),
-                    2 if login_state.logged_in => list_notes(),
-                    3 if login_state.logged_in => delete_note(),
+                    1 if login_state.logged_in => create_note(config),
+                    2 if login_state.logged_in => list_notes(config),
+                    3 if login_state.logged_in => delete_note(config),
+                    5 if login_state.logged_in => {
+                        sync_vault(config);
+                    }
                     4 => {
                         println!("Exiting...");
                         break;
@@ -203,49 +559,287 @@ fn run_interactive_menu() {
     }
 }
 
-/// Handles the login process
+/// Handles the GitHub login process through the gh CLI
 fn login() -> bool {
     println!("Logging in to GitHub...");
-    // Implement GitHub login logic here
-    let login_result: bool = run_cmd("gh auth login");
+    run_cmd("gh auth login")
+}
+
+/// Connects the vault on this device to its remote, asking for one if needed
+fn setup_vault(config: &Config) -> bool {
+    let repo_path = config.note_dir.as_str();
+
+    if let Ok(repo) = vault::Repo::open(repo_path) {
+        println!("The vault already exists. Pulling latest changes...");
+        drop(repo);
+        // Notes stay usable offline, so a failed pull is only reported
+        pull_repository(config);
+        return true;
+    }
+
+    // A remote from the config file needs no prompt
+    if let Some(url) = &config.remote {
+        return init_repository(config, url, config.branch.as_deref());
+    }
+
+    println!("Enter the git URL of your vault remote (SSH, HTTPS or file://),");
+    println!("or leave it empty to create a private GitHub repository with gh:");
+    let mut url = String::new();
+    if io::stdin().read_line(&mut url).is_err() {
+        println!("Error reading input.");
+        return false;
+    }
 
-    // Assuming run_cmd returns true if successful, false otherwise
-    return login_result;
+    match url.trim() {
+        "" => create_github_repository(config, config.branch.as_deref()),
+        url => init_repository(config, url, config.branch.as_deref()),
+    }
 }
 
-/// Handles the repository operations
-fn handle_repository() {
-    let repo_path = format!("{}/.prive-note", env::var("HOME").unwrap());
-    let repo_exists = Path::new(&repo_path).exists();
+/// Sets up the vault to sync with an existing git remote, optionally on a fixed branch
+fn init_repository(config: &Config, url: &str, branch: Option<&str>) -> bool {
+    let repo_path = config.note_dir.as_str();
+    println!("Connecting the vault at {} to {}", repo_path, url);
 
-    if repo_exists {
-        println!("Repository 'prive-note' already exists. Pulling latest changes...");
-        pull_repository(&repo_path);
-    } else {
-        println!("Repository 'prive-note' does not exist. Creating...");
-        create_repository(&repo_path);
+    match vault::Repo::init(repo_path, url, branch) {
+        Ok(repo) => println!("Syncing with branch '{}'.", repo.branch()),
+        Err(e) => {
+            eprintln!("Failed to set up the vault: {}", e);
+            return false;
+        }
+    }
+    // Committing on top of a failed pull would fork the vault from its remote
+    if !pull_repository(config) {
+        return false;
+    }
+
+    // Publish the ignore rules so an empty remote gets its first commit
+    match commit_vault_changes(config, &[], "init") {
+        Ok(_) => {
+            println!("The vault is ready.");
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to push to {}: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Creates a private GitHub repository with the gh CLI and sets up the vault to sync with it
+fn create_github_repository(config: &Config, branch: Option<&str>) -> bool {
+    let repo_path = config.note_dir.as_str();
+
+    println!("Are you already logged in to GitHub? (yes/no)");
+    let mut choice = String::new();
+    if io::stdin().read_line(&mut choice).is_err() {
+        println!("Error reading input.");
+        return false;
+    }
+    match choice.trim().to_lowercase().as_str() {
+        "yes" => {}
+        "no" => {
+            if !login() {
+                println!("Login failed.");
+                return false;
+            }
+            println!("Login successful. Proceeding to create the repository...");
+        }
+        _ => {
+            println!("Invalid choice. Exiting...");
+            return false;
+        }
+    }
+
+    println!("Creating repository 'prive-note' on GitHub");
+    run_cmd("gh repo create prive-note --private");
+
+    let output = Command::new("gh")
+        .args([
+            "repo",
+            "view",
+            "prive-note",
+            "--json",
+            "url",
+            "--jq",
+            ".url",
+        ])
+        .stderr(Stdio::inherit())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let url = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            println!("Cloning repository to {}", repo_path);
+            init_repository(config, &url, branch)
+        }
+        _ => {
+            println!("Failed to look up the GitHub repository 'prive-note'.");
+            false
+        }
     }
 }
 
 /// Pulls the latest changes from the repository
-fn pull_repository(repo_path: &str) {
-    if let Err(_) = env::set_current_dir(repo_path) {
-        println!("Failed to change directory to {}", repo_path);
-        return;
+fn pull_repository(config: &Config) -> bool {
+    let repo_path = config.note_dir.as_str();
+    let pulled = vault::Repo::open(repo_path).and_then(|repo| {
+        if let Some(url) = repo.remote_url() {
+            println!("Pulling repository from {}", url);
+        }
+        pull_vault(config, &repo)
+    });
+    match pulled {
+        Ok(outcome) => {
+            match outcome {
+                vault::repo::PullOutcome::UpToDate => println!("Already up to date."),
+                vault::repo::PullOutcome::FastForwarded => println!("Pulled the latest changes."),
+                vault::repo::PullOutcome::Merged => println!("Merged the latest changes."),
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to pull the repository: {}", e);
+            false
+        }
     }
+}
 
-    println!("Pulling repository from {}", repo_path);
-    run_cmd("git pull");
+/// Stages vault files together with the vault metadata, commits them and pushes the commit
+///
+/// Returns false if the commit was only made locally because the push failed;
+/// it is left for `prive sync` to push once the remote is reachable again.
+fn commit_vault_changes(
+    config: &Config,
+    files: &[&str],
+    action: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let note_dir = config.note_dir.as_str();
+    // Guard against plaintext ever being committed to the vault
+    vault::ensure_gitignore(note_dir)?;
+    // Notes must only be readable by the members the vault's policy allows
+    for file in files.iter().filter(|file| file.ends_with(".secured")) {
+        vault::policy::check(note_dir, file)?;
+    }
+    let repo = vault::Repo::open(note_dir)?;
+    vault::manifest::update(note_dir, &repo)?;
+
+    let mut paths = vault::metadata_files(note_dir);
+    paths.extend_from_slice(files);
+
+    repo.stage(&paths)?;
+    // Commit messages are built from the action and opaque note ids, never from note names
+    let note_ids = vault::manifest::note_ids(note_dir, files)?;
+    repo.commit(&repo.message(action, &note_ids))?;
+
+    // Another device may have pushed first: merge its changes and try once more
+    let pushed = repo.push().or_else(|e| match pull_vault(config, &repo)? {
+        vault::repo::PullOutcome::UpToDate => Err(e),
+        _ => repo.push(),
+    });
+    match pushed {
+        Ok(_) => {
+            if let Err(e) = vault::manifest::record_pushed(note_dir, &repo) {
+                println!("Failed to record the pushed manifest: {}", e);
+            }
+            Ok(true)
+        }
+        Err(e) => {
+            println!("Could not push to the remote: {}", e);
+            report_unsynced(&repo);
+            Ok(false)
+        }
+    }
 }
 
-/// Creates a new repository
-fn create_repository(repo_path: &str) {
-    println!("Creating repository at {}", repo_path);
-    run_cmd("gh repo create prive-note --private");
+/// Fetches the remote, checks its notes against the vault manifest and merges them
+///
+/// A merge rebuilds the manifest for the merged notes in a local commit.
+fn pull_vault(
+    config: &Config,
+    repo: &vault::Repo,
+) -> Result<vault::repo::PullOutcome, Box<dyn Error>> {
+    let note_dir = config.note_dir.as_str();
+    let Some(remote_oid) = repo.fetch()? else {
+        return Ok(vault::repo::PullOutcome::UpToDate);
+    };
+    if repo.contains(remote_oid)? {
+        vault::manifest::warn_if_stale(repo);
+        return Ok(vault::repo::PullOutcome::UpToDate);
+    }
+
+    repo.verify_incoming(remote_oid)?;
+    let rotated = vault::manifest::verify(note_dir, repo, &remote_oid.to_string())?;
+    let outcome = repo.merge(remote_oid, |conflict| {
+        vault::merge::resolve(config, conflict)
+    })?;
+    // A rotated vault key is only trusted once the commits carrying it were merged
+    if let Some(vault_key) = rotated {
+        vault::key::replace(note_dir, &vault_key)?;
+    }
+    if let vault::repo::PullOutcome::Merged = outcome {
+        if vault::manifest::update(note_dir, repo)? {
+            repo.stage(&vault::metadata_files(note_dir))?;
+            repo.commit(&repo.message("manifest", &[]))?;
+        }
+    }
+    vault::manifest::warn_if_stale(repo);
+    Ok(outcome)
+}
+
+/// Prints how many local commits are waiting to be pushed
+fn report_unsynced(repo: &vault::Repo) {
+    match repo.unsynced_commits() {
+        Ok(0) => {}
+        Ok(count) => println!(
+            "{} commit(s) not synced. Your changes are saved locally; run `prive sync` once the remote is reachable.",
+            count
+        ),
+        Err(e) => println!("Failed to count unsynced commits: {}", e),
+    }
+}
 
-    let target_dir = format!("{}/.prive-note", env::var("HOME").unwrap());
-    println!("Cloning repository to {}", target_dir);
-    run_cmd(&format!("gh repo clone prive-note {}", target_dir));
+/// Pulls the remote's changes, merging conflicting notes, and pushes local commits
+///
+/// Returns false when anything could not be pulled or pushed.
+fn sync_vault(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let repo = match vault::Repo::open(note_dir) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+
+    if let Err(e) = pull_vault(config, &repo) {
+        eprintln!("Failed to pull the repository: {}", e);
+        report_unsynced(&repo);
+        return false;
+    }
+    match repo.unsynced_commits() {
+        Ok(0) => {
+            println!("The vault is in sync.");
+            true
+        }
+        Ok(count) => match repo.push() {
+            Ok(_) => {
+                if let Err(e) = vault::manifest::record_pushed(note_dir, &repo) {
+                    eprintln!("Failed to record the pushed manifest: {}", e);
+                }
+                println!("Pushed {} commit(s).", count);
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to push: {}", e);
+                report_unsynced(&repo);
+                false
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to count unsynced commits: {}", e);
+            false
+        }
+    }
 }
 
 /// Runs a command in the shell
@@ -275,47 +869,17 @@ fn run_cmd(command: &str) -> bool {
     }
 }
 
-/// Runs a command in the shell
-fn run_cmd_result(command: &str) -> Result<(), Box<dyn Error>> {
-    let args: Vec<&str> = command.split_whitespace().collect();
-    let result = Command::new(args[0])
-        .args(&args[1..])
-        .stdin(Stdio::null())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-
-    if result.status.success() {
-        // println!("Command executed successfully.");
-        Ok(())
-    } else {
-        println!("Failed to execute command.");
-        Err("Command execution failed.".into())
-    }
+/// Returns the names of all secured notes in the note directory and its folders, sorted by name
+fn secured_note_files(note_dir: &str) -> io::Result<Vec<String>> {
+    vault::note_files(note_dir)
 }
 
-fn list_notes() {
-    let note_dir = format!("{}/.prive-note", env::var("HOME").unwrap());
-    let note_db = NoteDatabase::load();
-
-    match fs::read_dir(&note_dir) {
-        Ok(dir_contents) => {
-            let secured_files: Vec<String> = dir_contents
-                .filter_map(|entry| {
-                    entry.ok().and_then(|e| {
-                        if let Some(name) = e.file_name().to_str() {
-                            if name.ends_with(".secured") {
-                                Some(name.to_owned())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    })
-                })
-                .collect();
+fn list_notes(config: &Config) {
+    let note_dir = config.note_dir.as_str();
+    let note_db = NoteDatabase::load(config);
 
+    match secured_note_files(note_dir) {
+        Ok(secured_files) => {
             if secured_files.is_empty() {
                 println!("No secured notes found in ~/.prive-note.");
                 return;
@@ -330,13 +894,13 @@ fn list_notes() {
 
             let mut choice = String::new(); // Change to String
 
-            if let Ok(_) = io::stdin().read_line(&mut choice) {
+            if io::stdin().read_line(&mut choice).is_ok() {
                 // Read into String
                 if let Ok(choice) = choice.trim().parse::<usize>() {
                     if choice > 0 && choice <= secured_files.len() {
                         let selected_file = &secured_files[choice - 1];
                         println!("Viewing note: {}", selected_file);
-                        open_file_in_vim(&note_dir, selected_file);
+                        open_file_in_editor(config, selected_file, true);
                     } else {
                         println!(
                             "Invalid choice. Please enter a number between 1 and {}.",
@@ -357,29 +921,129 @@ fn list_notes() {
         }
     }
 }
-/// Opens a file in Vim for editing
-fn open_file_in_vim(note_dir: &str, file_name: &str) -> bool {
+/// Plaintext of a note together with the key that decrypted it
+type OpenedNote = (zeroize::Zeroizing<Vec<u8>>, Option<vault::key::NoteKey>);
+
+/// Reads and decrypts a secured note, prompting for its password
+///
+/// Returns the key that opened the note so it can be saved again without
+/// another prompt; legacy notes return no key and are re-keyed when saved.
+fn read_secured_note(
+    note_dir: &str,
+    secured_file_path: &Path,
+) -> Result<OpenedNote, Box<dyn Error>> {
+    let data = fs::read(secured_file_path)?;
+
+    // Notes that have not been saved since upgrading are still in the old format
+    if !crypto::is_prive_format(&data) {
+        eprintln!("This note uses the legacy format and will be upgraded when saved.");
+        return Ok((crypto::decrypt_legacy_file(secured_file_path)?, None));
+    }
+    decrypt_note_data(note_dir, &secured_file_path.to_string_lossy(), &data)
+}
+
+/// Decrypts the contents of a secured note in the current format, prompting for its key
+fn decrypt_note_data(
+    note_dir: &str,
+    file: &str,
+    data: &[u8],
+) -> Result<OpenedNote, Box<dyn Error>> {
+    let key = match crypto::key_mode(data)? {
+        crypto::KeyMode::Vault => vault::policy::note_key(note_dir, file)?,
+        crypto::KeyMode::Password => {
+            vault::key::NoteKey::Password(prompt::password("Enter the password for the note:")?)
+        }
+    };
+    let plaintext = key.decrypt(data)?;
+    Ok((plaintext, Some(key)))
+}
+
+/// Returns the vault key for a note, setting up the master passphrase on first use
+///
+/// Notes in a folder with its own readers get that folder's key instead.
+fn vault_note_key(note_dir: &str, file: &str) -> Result<vault::key::NoteKey, Box<dyn Error>> {
+    if vault::key::exists(note_dir) {
+        return vault::policy::note_key(note_dir, file);
+    }
+
+    // The new vault key file is committed together with the note that needed it
+    let vault_key = vault::key::create(note_dir)?;
+    Ok(vault::key::NoteKey::Vault(vault_key))
+}
+
+/// Asks whether a note uses the vault key or its own password and prompts accordingly
+fn choose_note_key(note_dir: &str, file: &str) -> Result<vault::key::NoteKey, Box<dyn Error>> {
+    println!("Protect the note with the vault master passphrase or a separate password? (vault/separate)");
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice)?;
+
+    match choice.trim().to_lowercase().as_str() {
+        "" | "vault" => vault_note_key(note_dir, file),
+        "separate" => Ok(vault::key::NoteKey::Password(prompt::new_password(
+            "Enter a password for the note:",
+        )?)),
+        _ => Err("Invalid choice, please enter vault or separate.".into()),
+    }
+}
+
+/// Opens a secured note in the editor
+///
+/// With `confirm` the user is asked before saving; otherwise changes are saved
+/// as soon as the editor exits.
+fn open_file_in_editor(config: &Config, file_name: &str, confirm: bool) -> bool {
+    let note_dir = config.note_dir.as_str();
     let secured_file_path = format!("{}/{}", note_dir, file_name);
-    let decrypted_file_path = format!("{}/{}", note_dir, &file_name[..file_name.len() - 8]); // Remove ".secured" from file name
 
     // Decrypt the secured file
-    if !run_cmd(&format!("secured decrypt {}", secured_file_path)) {
-        println!("Error: Failed to decrypt the file.");
+    let (plaintext, key) = match read_secured_note(note_dir, Path::new(&secured_file_path)) {
+        Ok(opened) => opened,
+        Err(e) => {
+            println!("Error: Failed to decrypt the file: {}", e);
+            return false;
+        }
+    };
+    edit_plaintext(config, file_name, &plaintext, key, confirm)
+}
+
+/// Edits plaintext in a private working copy and offers to save it back to the secured file
+fn edit_plaintext(
+    config: &Config,
+    file_name: &str,
+    plaintext: &[u8],
+    key: Option<vault::key::NoteKey>,
+    confirm: bool,
+) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let secured_file_path = format!("{}/{}", note_dir, file_name);
+    let note_name = file_name.strip_suffix(".secured").unwrap_or(file_name);
+
+    // The decrypted copy lives in a private runtime directory, never in the vault
+    let working_copy = match workspace::WorkingCopy::create(note_name, plaintext) {
+        Ok(working_copy) => working_copy,
+        Err(e) => {
+            println!("Error: Failed to write the decrypted note: {}", e);
+            return false;
+        }
+    };
+
+    // Open the decrypted file in the configured editor
+    if let Err(err) = editor::edit(config, working_copy.path()) {
+        println!("Error opening note: {}", err);
         return false;
     }
 
-    // Open the decrypted file in Vim
-    match Command::new("nvim").arg(&decrypted_file_path).status() {
-        Ok(status) => {
-            if !status.success() {
-                println!("Failed to open the file in Vim.");
-                return false;
+    if !confirm {
+        return match working_copy.read() {
+            Ok(edited) if edited.as_slice() == plaintext => {
+                println!("No changes to save.");
+                true
             }
-        }
-        Err(err) => {
-            println!("Error opening note: {}", err);
-            return false;
-        }
+            Ok(_) => save_changes(config, &secured_file_path, &working_copy, key),
+            Err(e) => {
+                println!("Error: Failed to read the edited note: {}", e);
+                false
+            }
+        };
     }
 
     println!("Editing session finished. Do you want to save changes?");
@@ -387,13 +1051,10 @@ fn open_file_in_vim(note_dir: &str, file_name: &str) -> bool {
     println!("2. Discard changes");
 
     let mut choice = String::new();
-    if let Ok(_) = io::stdin().read_line(&mut choice) {
+    if io::stdin().read_line(&mut choice).is_ok() {
         match choice.trim().parse::<u32>() {
             Ok(choice) => match choice {
-                1 => {
-                    save_changes(&decrypted_file_path);
-                    true
-                }
+                1 => save_changes(config, &secured_file_path, &working_copy, key),
                 2 => {
                     println!("Changes discarded.");
                     false
@@ -413,191 +1074,156 @@ fn open_file_in_vim(note_dir: &str, file_name: &str) -> bool {
         false
     }
 }
-// /// Saves changes made to a file
-// fn save_changes(file_path: &str) {
-//     let target_dir = format!("{}/.prive-note/", env::var("HOME").unwrap());
-
-//     if let Err(_) = env::set_current_dir(&target_dir) {
-//         println!("Failed to change directory to {}", target_dir);
-//         return;
-//     }
 
-//     // Encrypt the file
-//     let encrypted_file = format!("{}", file_path);
-//     let encrypted_file_secure = format!("{}.secured", file_path);
-//     run_cmd(&format!("secured encrypt {}", &file_path));
-//     // Delete the original after encrypt
-//     run_cmd(&format!("rm -rf {}", &file_path));
-
-//     // Add, commit, and push the encrypted file
-//     run_cmd(&format!("git add {}", encrypted_file_secure));
-//     run_cmd("git commit -m 'update'");
-//     run_cmd("git push origin main");
-
-//     println!("Changes committed and pushed successfully.");
-// }
-/// Saves changes made to a file
-fn save_changes(file_path: &str) {
-    let target_dir = format!("{}/.prive-note/", env::var("HOME").unwrap());
-
-    if let Err(_) = env::set_current_dir(&target_dir) {
+/// Saves changes made to a working copy, re-encrypting it with the key that opened it
+///
+/// Returns true once the note is encrypted and committed.
+fn save_changes(
+    config: &Config,
+    secured_file_path: &str,
+    working_copy: &workspace::WorkingCopy,
+    key: Option<vault::key::NoteKey>,
+) -> bool {
+    let target_dir = config.note_dir.as_str();
+
+    if env::set_current_dir(target_dir).is_err() {
         println!("Failed to change directory to {}", target_dir);
-        return;
+        return false;
     }
 
-    // Encrypt the file with password verification
-    let encrypted_file = format!("{}", file_path);
-    let encrypted_file_secure = format!("{}.secured", file_path);
-    loop {
-        println!("Enter a password to encrypt the note:");
-        let mut password = String::new();
-        if let Ok(_) = io::stdin().read_line(&mut password) {
-            password = password.trim().to_string();
-            if run_cmd(&format!("secured encrypt {} -p {}", &file_path, &password)) {
-                break;
-            } else {
-                println!("Error: Incorrect password. Please try again.");
-            }
-        } else {
-            println!("Error reading input.");
-            return;
+    let plaintext = match working_copy.read() {
+        Ok(plaintext) => plaintext,
+        Err(e) => {
+            println!("Error: Failed to read the edited note: {}", e);
+            return false;
+        }
+    };
+
+    // Encrypt the file
+    let key = match key.map_or_else(|| choose_note_key(target_dir, secured_file_path), Ok) {
+        Ok(key) => key,
+        Err(e) => {
+            println!("Error: {}", e);
+            return false;
         }
+    };
+    if let Err(e) = key.encrypt_file(Path::new(secured_file_path), &plaintext) {
+        println!("Error: Failed to encrypt the note: {}", e);
+        return false;
     }
-    
-    // Delete the original after encrypt
-    run_cmd(&format!("rm -rf {}", &file_path));
 
     // Add, commit, and push the encrypted file
-    std::thread::sleep(Duration::from_secs(1));
-
-    run_cmd(&format!("git add {}", encrypted_file_secure));
-    std::thread::sleep(Duration::from_secs(1));
-    run_cmd("git commit -m 'update'");
-    std::thread::sleep(Duration::from_secs(1));
-    run_cmd("git push origin main");
-
-    println!("Changes committed and pushed successfully.");
+    match commit_vault_changes(config, &[secured_file_path], "update") {
+        Ok(true) => println!("Changes committed and pushed successfully."),
+        Ok(false) => {}
+        Err(e) => {
+            println!("Failed to commit and push the changes: {}", e);
+            return false;
+        }
+    }
+    true
 }
 
-fn create_note() {
-    let note_dir = format!("{}/.prive-note", env::var("HOME").unwrap());
-    let mut note_db = NoteDatabase::load();
+fn create_note(config: &Config) {
+    let note_dir = config.note_dir.as_str();
 
-    env::set_current_dir(&note_dir);
-
-    if !Path::new(&note_dir).exists() {
+    if env::set_current_dir(note_dir).is_err() || !Path::new(&note_dir).exists() {
         println!("Error: The note directory doesn't exist.");
         return;
     }
 
     println!("Enter the name of the new note:");
     let mut note_name = String::new();
-    if let Ok(_) = io::stdin().read_line(&mut note_name) {
+    if io::stdin().read_line(&mut note_name).is_ok() {
         let note_name = note_name.trim();
-        if note_name.is_empty() {
-            println!("Error: Note name cannot be empty.");
+        if let Err(e) = validate_note_name(note_name) {
+            println!("Error: {}", e);
             return;
         }
 
-        let file_path = format!("{}/{}", note_dir, note_name);
         // Encrypt note name
         let secured_note_name = format!("{}.secured", note_name);
-        // Prompt the user to enter a password
-        println!("Enter a password for the note:");
-        let mut password = String::new();
-        if let Ok(_) = io::stdin().read_line(&mut password) {
-            // Remove newline characters from the password
-            password = password.trim().to_string();
-
-            println!("Do you want to set a password hint? (yes/no)");
-            let mut hint_choice = String::new();
-            if let Ok(_) = io::stdin().read_line(&mut hint_choice) {
-                if hint_choice.trim().eq_ignore_ascii_case("yes") {
-                    println!("Enter the password hint:");
-                    let mut password_hint = String::new();
-                    if let Ok(_) = io::stdin().read_line(&mut password_hint) {
-                        note_db.set_password_hint(&secured_note_name, password_hint.trim().to_string());
-                        note_db.save();
-                    }
-                }
-            }
+        let encrypted_file_path = format!("{}/{}", note_dir, secured_note_name);
+        if Path::new(&encrypted_file_path).exists() {
+            println!("Failed to create note: '{}' already exists.", note_name);
+            return;
+        }
 
-            // Create the note file with the template content
-            match OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&file_path)
-            {
-                Ok(mut file) => {
-                    // Write template content to the file
-                    if let Err(e) = writeln!(file, "Title: {}", note_name) {
-                        println!("Failed to write template content to file: {}", e);
-                        // Delete the file if writing the template content fails
-                        if let Err(e) = std::fs::remove_file(&file_path) {
-                            println!("Failed to delete file: {}", e);
+        // Prompt for the vault passphrase or a separate password without echoing it
+        match choose_note_key(note_dir, &secured_note_name) {
+            Ok(key) => {
+                // Hints only make sense for notes with their own password
+                let mut hint = None;
+                if key.mode() == crypto::KeyMode::Password {
+                    println!("Do you want to set a password hint? (yes/no)");
+                    let mut hint_choice = String::new();
+                    if io::stdin().read_line(&mut hint_choice).is_ok()
+                        && hint_choice.trim().eq_ignore_ascii_case("yes")
+                    {
+                        println!("Enter the password hint:");
+                        let mut password_hint = String::new();
+                        if io::stdin().read_line(&mut password_hint).is_ok() {
+                            hint = Some(password_hint.trim().to_string());
                         }
-                        return;
-                    }
-                    println!("Note '{}' created successfully.", note_name);
-                }
-                Err(e) => {
-                    println!("Failed to create note: {}", e);
-                    return;
-                }
-            }
-
-            // Encrypt the note file
-            let encrypted_file_path = format!("{}.secured", file_path);
-            match run_cmd_result(&format!("secured encrypt {} -p {}", &file_path, &password)) {
-                Ok(_) => println!("Note '{}' encrypted successfully.", note_name),
-                Err(e) => {
-                    println!("Failed to encrypt note file: {}", e);
-                    // Delete the unencrypted file if encryption fails
-                    if let Err(e) = std::fs::remove_file(&file_path) {
-                        println!("Failed to delete unencrypted file: {}", e);
                     }
                 }
+                write_new_note(config, note_name, &key, hint);
             }
-        } else {
-            println!("Failed to read password input.");
+            Err(e) => println!("Failed to read password input: {}", e),
         }
     } else {
         println!("Failed to read input.");
     }
-    // After encrypting and pushing the encrypted file
-    println!("Changes committed and pushed successfully.");
+}
 
-    std::thread::sleep(Duration::from_secs(1));
-    // git commit note-db.json to github
-    // Add, commit, and push the encrypted file
-    run_cmd("git add note-db.json");
-    std::thread::sleep(Duration::from_secs(1));
-    run_cmd("git commit -m 'update'");
-    std::thread::sleep(Duration::from_secs(1));
-    run_cmd("git push origin main");
+/// Encrypts a new note's template with the given key and commits it together with its hint
+fn write_new_note(
+    config: &Config,
+    note_name: &str,
+    key: &vault::key::NoteKey,
+    hint: Option<String>,
+) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let secured_note_name = format!("{}.secured", note_name);
+    let encrypted_file_path = format!("{}/{}", note_dir, secured_note_name);
+
+    if let Some(hint) = hint {
+        let mut note_db = NoteDatabase::load(config);
+        note_db.set_password_hint(&secured_note_name, hint);
+        note_db.save(config);
+    }
+
+    // Encrypt the template content straight into the note file
+    let template = format!("Title: {}\n", note_name);
+    let encrypted = create_note_folder(Path::new(&encrypted_file_path))
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|_| key.encrypt_file(Path::new(&encrypted_file_path), template.as_bytes()));
+    match encrypted {
+        Ok(_) => println!("Note '{}' created and encrypted successfully.", note_name),
+        Err(e) => {
+            println!("Failed to encrypt note file: {}", e);
+            return false;
+        }
+    }
+
+    // Add, commit, and push the encrypted file together with its hint
+    match commit_vault_changes(config, &[&secured_note_name, "note-db.json"], "create") {
+        Ok(true) => println!("Changes committed and pushed successfully."),
+        Ok(false) => {}
+        Err(e) => {
+            println!("Failed to commit and push the new note: {}", e);
+            return false;
+        }
+    }
+    true
 }
+
 /// Deletes a note
-fn delete_note() {
-    let note_dir = format!("{}/.prive-note", env::var("HOME").unwrap());
-
-    match fs::read_dir(&note_dir) {
-        Ok(dir_contents) => {
-            let secured_files: Vec<String> = dir_contents
-                .filter_map(|entry| {
-                    entry.ok().and_then(|e| {
-                        if let Some(name) = e.file_name().to_str() {
-                            if name.ends_with(".secured") {
-                                Some(name.to_owned())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    })
-                })
-                .collect();
+fn delete_note(config: &Config) {
+    let note_dir = config.note_dir.as_str();
 
+    match secured_note_files(note_dir) {
+        Ok(secured_files) => {
             if secured_files.is_empty() {
                 println!("No secured notes found in ~/.prive-note.");
                 return;
@@ -610,30 +1236,12 @@ fn delete_note() {
 
             let mut choice = String::new();
 
-            if let Ok(_) = io::stdin().read_line(&mut choice) {
+            if io::stdin().read_line(&mut choice).is_ok() {
                 if let Ok(choice) = choice.trim().parse::<usize>() {
                     if choice > 0 && choice <= secured_files.len() {
                         let selected_file = &secured_files[choice - 1];
                         println!("Deleting note: {}", selected_file);
-
-                        let target_dir = format!("{}/.prive-note/", env::var("HOME").unwrap());
-
-                        if let Err(_) = env::set_current_dir(&target_dir) {
-                            println!("Failed to change directory to {}", target_dir);
-                            return;
-                        }
-
-                        // Encrypt the file
-                        let encrypted_file_secure = format!("{}", selected_file);
-                        // Add, commit, and push the encrypted file
-                        run_cmd(&format!("git rm -rf {}", encrypted_file_secure));
-                        std::thread::sleep(Duration::from_secs(1));
-                        let commit_message = format!("Delete note: {}", selected_file);
-                        run_cmd("git commit -m remove");
-                        std::thread::sleep(Duration::from_secs(1));
-                        run_cmd("git push origin main");
-
-                        println!("Changes committed and pushed successfully.");
+                        remove_note(config, selected_file);
                     } else {
                         println!(
                             "Invalid choice. Please enter a number between 1 and {}.",
@@ -654,38 +1262,1482 @@ fn delete_note() {
         }
     }
 }
-/// Opens a note in Vim, encrypts it back after editing and exiting Vim, and deletes the original note file
-fn open_note(note: &str) {
-    let note_path = format!("{}/.prive-note/{}", env::var("HOME").unwrap(), note);
-    let encrypted_note_path = format!("{}.secured", note_path);
 
-    // Check if the original note file exists
-    if Path::new(&note_path).exists() {
-        // Open the note in Vim
-        if let Err(err) = Command::new("vim").arg(&note_path).status() {
-            eprintln!("Error opening note: {}", err);
-            return;
-        }
+/// Deletes a note's secured file and password hint, and commits the removal
+fn remove_note(config: &Config, file_name: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let file_path = format!("{}/{}", note_dir, file_name);
+    if let Err(e) = fs::remove_file(&file_path) {
+        println!("Failed to delete '{}': {}", file_name, e);
+        return false;
+    }
 
-        // After exiting Vim, encrypt the note back
-        if let Err(err) = Command::new("secured")
-            .args(&["encrypt", &note_path])
-            .status()
-        {
-            eprintln!("Error encrypting note: {}", err);
+    let mut note_db = NoteDatabase::load(config);
+    if note_db.password_hints.remove(file_name).is_some() {
+        note_db.save(config);
+    }
+
+    // Remove, commit, and push the encrypted file
+    match commit_vault_changes(config, &[file_name, "note-db.json"], "remove") {
+        Ok(true) => println!("Changes committed and pushed successfully."),
+        Ok(false) => {}
+        Err(e) => {
+            println!("Failed to commit and push the removal: {}", e);
+            return false;
         }
+    }
+    true
+}
 
-        // Delete the original note file
-        if let Err(err) = fs::remove_file(&note_path) {
-            eprintln!("Error deleting original note: {}", err);
+/// Exits with a failure status when a non-interactive command did not succeed
+fn exit_on_failure(succeeded: bool) {
+    if !succeeded {
+        process::exit(1);
+    }
+}
+
+/// Checks that a note name can be used as a file name in the vault
+///
+/// Notes may live in folders, such as `prod/db`.
+fn validate_note_name(note_name: &str) -> Result<(), String> {
+    if note_name.is_empty() {
+        return Err("Note name cannot be empty.".to_string());
+    }
+    if note_name
+        .split('/')
+        .any(|part| part.is_empty() || part.starts_with('.'))
+    {
+        return Err(format!("'{}' is not a valid note name.", note_name));
+    }
+    Ok(())
+}
+
+/// Returns the secured file name of a note named on the command line
+///
+/// Names that are not valid note names, such as `../x`, are refused so a
+/// command never reaches outside the vault.
+fn checked_file_name(note: &str) -> Result<String, String> {
+    validate_note_name(note.strip_suffix(".secured").unwrap_or(note))?;
+    Ok(secured_file_name(note))
+}
+
+/// Creates the folder a note lives in
+fn create_note_folder(secured_file_path: &Path) -> io::Result<()> {
+    match secured_file_path.parent() {
+        Some(folder) => fs::create_dir_all(folder),
+        None => Ok(()),
+    }
+}
+
+/// Returns the secured file name of an existing note, printing an error when there is none
+fn existing_note(note_dir: &str, note: &str) -> Option<String> {
+    let file_name = match checked_file_name(note) {
+        Ok(file_name) => file_name,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return None;
         }
+    };
+    if Path::new(note_dir).join(&file_name).is_file() {
+        Some(file_name)
     } else {
-        // If the original note file doesn't exist, check if the corresponding encrypted note exists
-        if Path::new(&encrypted_note_path).exists() {
-            // Encrypted note exists, remove it
-            if let Err(err) = fs::remove_file(&encrypted_note_path) {
-                eprintln!("Error removing encrypted note: {}", err);
+        eprintln!("Note '{}' does not exist.", note);
+        None
+    }
+}
+
+/// Creates a note without prompting, unless it needs its own password or a new vault key
+fn new_note(config: &Config, note: &str, separate: bool, hint: Option<String>) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let note_name = note.strip_suffix(".secured").unwrap_or(note);
+
+    if let Err(e) = validate_note_name(note_name) {
+        eprintln!("Error: {}", e);
+        return false;
+    }
+    if !Path::new(&note_dir).is_dir() {
+        eprintln!("Error: The note directory doesn't exist.");
+        return false;
+    }
+    if Path::new(&note_dir)
+        .join(secured_file_name(note_name))
+        .exists()
+    {
+        eprintln!("Failed to create note: '{}' already exists.", note_name);
+        return false;
+    }
+
+    let key = if separate {
+        prompt::new_password("Enter a password for the note:").map(vault::key::NoteKey::Password)
+    } else {
+        vault_note_key(note_dir, &secured_file_name(note_name))
+    };
+    match key {
+        Ok(key) => write_new_note(config, note_name, &key, hint),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            false
+        }
+    }
+}
+
+/// Secured files of all notes together with the note database's password hints
+#[derive(Serialize)]
+struct NoteList {
+    notes: Vec<String>,
+    #[serde(flatten)]
+    database: NoteDatabase,
+}
+
+/// Prints the name of every note, one per line
+fn print_note_names(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let list = secured_note_files(note_dir)
+        .map(|notes| NoteList {
+            notes,
+            database: NoteDatabase::load(config),
+        })
+        .map_err(|e| format!("Failed to list secured notes: {}", e));
+
+    output::report("ls", list, |list| {
+        for file in &list.notes {
+            println!("{}", file.strip_suffix(".secured").unwrap_or(file));
+        }
+    })
+}
+
+/// Whether the vault is set up on this device and how it relates to its remote
+#[derive(Serialize)]
+struct VaultStatus {
+    #[serde(flatten)]
+    login: LoginState,
+    vault_name: Option<String>,
+    vault: String,
+    remote: Option<String>,
+    branch: Option<String>,
+    notes: usize,
+    unsynced_commits: Option<usize>,
+    agent_running: bool,
+}
+
+/// Prints whether the vault is set up, where it syncs to and what is waiting to be pushed
+fn show_status(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let repo = vault::Repo::open(note_dir).ok();
+    let status = VaultStatus {
+        login: LoginState::load(config),
+        remote: repo.as_ref().and_then(|repo| repo.remote_url()),
+        branch: repo.as_ref().map(|repo| repo.branch().to_string()),
+        notes: secured_note_files(note_dir).map_or(0, |files| files.len()),
+        unsynced_commits: repo.as_ref().and_then(|repo| repo.unsynced_commits().ok()),
+        agent_running: agent::is_running(note_dir),
+        vault_name: config.vault_name.clone(),
+        vault: note_dir.to_string(),
+    };
+
+    output::report("status", Ok(status), |status| {
+        if !status.login.logged_in {
+            match &status.vault_name {
+                Some(name) => println!(
+                    "The vault '{}' is not set up on this device; run `prive --vault {} init`.",
+                    name, name
+                ),
+                None => println!("The vault is not set up on this device; run `prive init`."),
+            }
+            return;
+        }
+        match &status.vault_name {
+            Some(name) => println!("Vault: {} ({})", name, status.vault),
+            None => println!("Vault: {}", status.vault),
+        }
+        if let (Some(remote), Some(branch)) = (&status.remote, &status.branch) {
+            println!("Remote: {} (branch {})", remote, branch);
+        }
+        println!("Notes: {}", status.notes);
+        if let Some(count) = status.unsynced_commits {
+            println!("Unsynced commits: {}", count);
+        }
+        if status.agent_running {
+            println!("Agent: unlocked");
+        } else {
+            println!("Agent: locked");
+        }
+    })
+}
+
+/// Opens a note in the editor and saves it without asking once the editor exits
+fn edit_note(config: &Config, note: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    match existing_note(note_dir, note) {
+        Some(file_name) => open_file_in_editor(config, &file_name, false),
+        None => false,
+    }
+}
+
+/// Prints a decrypted note to stdout
+fn cat_note(config: &Config, note: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let Some(file_name) = existing_note(note_dir, note) else {
+        return false;
+    };
+
+    match read_secured_note(note_dir, &Path::new(&note_dir).join(&file_name)) {
+        Ok((plaintext, _)) => match io::stdout().write_all(&plaintext) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Failed to print the note: {}", e);
+                false
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to decrypt '{}': {}", note, e);
+            false
+        }
+    }
+}
+
+/// Encrypts stdin into a note, replacing its content or appending to it
+///
+/// The plaintext is only ever held in memory. Existing notes keep the key that
+/// protects them; new notes are protected by the vault key.
+fn write_note_from_stdin(config: &Config, note: &str, append: bool) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let note_name = note.strip_suffix(".secured").unwrap_or(note);
+    if let Err(e) = validate_note_name(note_name) {
+        eprintln!("Error: {}", e);
+        return false;
+    }
+    if !Path::new(&note_dir).is_dir() {
+        eprintln!("Error: The note directory doesn't exist.");
+        return false;
+    }
+    let file_name = secured_file_name(note_name);
+    let file_path = Path::new(&note_dir).join(&file_name);
+
+    let input = match prompt::read_stdin() {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Failed to read stdin: {}", e);
+            return false;
+        }
+    };
+
+    // Opening an existing note checks its password before anything is overwritten
+    let exists = file_path.is_file();
+    let (existing, key) = if exists {
+        match read_secured_note(note_dir, &file_path) {
+            Ok(opened) => opened,
+            Err(e) => {
+                eprintln!("Failed to decrypt '{}': {}", note_name, e);
+                return false;
+            }
+        }
+    } else {
+        (zeroize::Zeroizing::new(Vec::new()), None)
+    };
+    let key = match key.map_or_else(|| vault_note_key(note_dir, &file_name), Ok) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return false;
+        }
+    };
+
+    let plaintext = if append {
+        let mut plaintext =
+            zeroize::Zeroizing::new(Vec::with_capacity(existing.len() + input.len()));
+        plaintext.extend_from_slice(&existing);
+        plaintext.extend_from_slice(&input);
+        plaintext
+    } else {
+        input
+    };
+    let encrypted = create_note_folder(&file_path)
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|_| key.encrypt_file(&file_path, &plaintext));
+    if let Err(e) = encrypted {
+        eprintln!("Failed to encrypt '{}': {}", note_name, e);
+        return false;
+    }
+
+    let action = if exists { "update" } else { "create" };
+    match commit_vault_changes(config, &[&file_name], action) {
+        Ok(true) => println!("Changes committed and pushed successfully."),
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Failed to commit and push '{}': {}", note_name, e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Deletes a note named on the command line
+fn rm_note(config: &Config, note: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    match existing_note(note_dir, note) {
+        Some(file_name) => remove_note(config, &file_name),
+        None => false,
+    }
+}
+
+/// Renames a note together with its password hint and commits the move
+fn move_note(config: &Config, from: &str, to: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let Some(from_file) = existing_note(note_dir, from) else {
+        return false;
+    };
+    let to_name = to.strip_suffix(".secured").unwrap_or(to);
+    if let Err(e) = validate_note_name(to_name) {
+        eprintln!("Error: {}", e);
+        return false;
+    }
+    let to_file = secured_file_name(to_name);
+    if Path::new(&note_dir).join(&to_file).exists() {
+        eprintln!("Failed to rename note: '{}' already exists.", to_name);
+        return false;
+    }
+
+    if let Err(e) = move_secured_file(note_dir, &from_file, &to_file) {
+        eprintln!("Failed to rename '{}': {}", from, e);
+        return false;
+    }
+    let mut note_db = NoteDatabase::load(config);
+    if let Some(hint) = note_db.password_hints.remove(&from_file) {
+        note_db.set_password_hint(&to_file, hint);
+        note_db.save(config);
+    }
+
+    match commit_vault_changes(config, &[&from_file, &to_file, "note-db.json"], "move") {
+        Ok(true) => println!("Changes committed and pushed successfully."),
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Failed to commit and push the rename: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Moves a secured file, re-encrypting vault notes that move into a folder with other readers
+fn move_secured_file(note_dir: &str, from_file: &str, to_file: &str) -> Result<(), Box<dyn Error>> {
+    let from_path = Path::new(note_dir).join(from_file);
+    let to_path = Path::new(note_dir).join(to_file);
+    create_note_folder(&to_path)?;
+
+    let data = fs::read(&from_path)?;
+    let same_scope =
+        vault::policy::scope(note_dir, from_file) == vault::policy::scope(note_dir, to_file);
+    if same_scope
+        || !crypto::is_prive_format(&data)
+        || crypto::key_mode(&data)? != crypto::KeyMode::Vault
+    {
+        fs::rename(from_path, to_path)?;
+        return Ok(());
+    }
+    let (plaintext, _) = decrypt_note_data(note_dir, from_file, &data)?;
+    vault::policy::note_key(note_dir, to_file)?.encrypt_file(&to_path, &plaintext)?;
+    fs::remove_file(from_path)?;
+    Ok(())
+}
+
+/// A note's password hint, if it has one
+#[derive(Serialize)]
+struct NoteHint {
+    note: String,
+    hint: Option<String>,
+}
+
+/// Prints a note's password hint, or sets it when a new hint is given
+fn note_hint(config: &Config, note: &str, hint: Option<String>) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let mut note_db = NoteDatabase::load(config);
+
+    let Some(hint) = hint else {
+        let result = checked_file_name(note).and_then(|file_name| {
+            if Path::new(&note_dir).join(&file_name).is_file() {
+                Ok(NoteHint {
+                    hint: note_db.get_password_hint(&file_name),
+                    note: file_name,
+                })
+            } else {
+                Err(format!("Note '{}' does not exist.", note))
+            }
+        });
+        return output::report("hint", result, |result| {
+            println!("{}", result.hint.as_deref().unwrap_or("No hint"));
+        });
+    };
+    let Some(file_name) = existing_note(note_dir, note) else {
+        return false;
+    };
+    note_db.set_password_hint(&file_name, hint);
+    note_db.save(config);
+    match commit_vault_changes(config, &["note-db.json"], "hint") {
+        Ok(true) => println!("Changes committed and pushed successfully."),
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Failed to commit and push the hint: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Moves a plaintext note out of the vault, encrypts it and opens it for editing
+fn open_note(config: &Config, note: &str) {
+    let note_dir = config.note_dir.as_str();
+    let note_path = format!("{}/{}", note_dir, note);
+    let encrypted_note_path = format!("{}.secured", note_path);
+
+    // Check if the original note file exists
+    if Path::new(&note_path).exists() {
+        // Encrypt the plaintext the same way the note was protected before
+        let key = match existing_note_key(note_dir, &encrypted_note_path) {
+            Ok(key) => key,
+            Err(err) => {
+                eprintln!("Error reading password: {}", err);
+                return;
+            }
+        };
+        let plaintext = match fs::read(&note_path) {
+            Ok(plaintext) => zeroize::Zeroizing::new(plaintext),
+            Err(err) => {
+                eprintln!("Error reading note: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = key.encrypt_file(Path::new(&encrypted_note_path), &plaintext) {
+            eprintln!("Error encrypting note: {}", err);
+            return;
+        }
+
+        // Wipe the plaintext from the vault and continue editing in a private working copy
+        if let Err(err) = workspace::wipe_file(Path::new(&note_path)) {
+            eprintln!("Error deleting original note: {}", err);
+        }
+        edit_plaintext(
+            config,
+            &format!("{}.secured", note),
+            &plaintext,
+            Some(key),
+            true,
+        );
+    } else if Path::new(&encrypted_note_path).exists() {
+        // Only the encrypted note exists, so open it normally
+        open_file_in_editor(config, &format!("{}.secured", note), true);
+    } else {
+        eprintln!("Note '{}' does not exist.", note);
+    }
+}
+
+/// Prints a secured file decrypted when the agent holds the vault key, for `git diff`
+///
+/// Git runs this without a terminal, so it never prompts; notes it cannot
+/// decrypt are shown as a one-line placeholder instead of binary data.
+/// Git runs it from the top of the vault's checkout, which selects the agent.
+fn git_textconv(config: &Config, file: &str) -> bool {
+    let note_dir = env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| config.note_dir.clone());
+    let data = match fs::read(file) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", file, e);
+            return false;
+        }
+    };
+
+    let plaintext = if !crypto::is_prive_format(&data) {
+        Err("legacy format")
+    } else {
+        match crypto::key_mode(&data) {
+            Ok(crypto::KeyMode::Vault) if agent::is_running(&note_dir) => {
+                agent::decrypt(&note_dir, &data)
+                    .map_err(|_| "cannot be decrypted with the vault key")
+            }
+            Ok(crypto::KeyMode::Vault) => Err("vault is locked"),
+            Ok(crypto::KeyMode::Password) => Err("protected by its own password"),
+            Err(_) => Err("unreadable header"),
+        }
+    };
+
+    let written = match plaintext {
+        Ok(plaintext) => io::stdout().write_all(&plaintext),
+        Err(reason) => writeln!(io::stdout(), "[encrypted note: {}]", reason),
+    };
+    match written {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Failed to write the decrypted note: {}", e);
+            false
+        }
+    }
+}
+
+/// Registers `prive git-textconv` as the diff driver for notes in the vault
+fn install_textconv(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+
+    let installed = env::current_exe()
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|exe| {
+            let command = format!("'{}' git-textconv", exe.display());
+            vault::Repo::open(note_dir)?.install_textconv(vault::DIFF_DRIVER, &command)?;
+            vault::ensure_gitattributes(note_dir)?;
+            commit_vault_changes(config, &[], "textconv")
+        });
+    match installed {
+        Ok(_) => {
+            println!(
+                "git diff and git log -p now show decrypted notes while the vault is unlocked."
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to install the textconv driver: {}", e);
+            false
+        }
+    }
+}
+
+/// Lists the device keys trusted to sign vault commits
+fn list_trusted_devices(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let path = Path::new(&note_dir).join(vault::signing::TRUSTED_SIGNERS_FILE);
+    let trusted = fs::read_to_string(path)
+        .map(|text| vault::signing::parse_trusted(&text))
+        .unwrap_or_default();
+
+    output::report("trust", Ok(trusted), |trusted| {
+        if trusted.is_empty() {
+            println!("No trusted devices yet. Run `prive trust <device>` on each device.");
+        }
+        for signer in trusted {
+            println!("{}", signer.to_line());
+        }
+    })
+}
+
+/// Adds a device key to the vault's trusted signers and commits it
+///
+/// Without a key, this device's own signing key from git config is trusted.
+fn trust_device(config: &Config, device: &str, key: Option<&str>) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let path = Path::new(&note_dir).join(vault::signing::TRUSTED_SIGNERS_FILE);
+
+    let signer = match key {
+        Some(key) if Path::new(key).exists() => {
+            vault::signing::signer_for(device, &vault::signing::SigningKey::Ssh(key.to_string()))
+        }
+        Some(key) => {
+            vault::signing::signer_for(device, &vault::signing::SigningKey::Gpg(key.to_string()))
+        }
+        None => vault::Repo::open(note_dir).and_then(|repo| {
+            let key = repo.signing_key().ok_or(
+                "No signing key is configured; set user.signingkey (and gpg.format for SSH keys).",
+            )?;
+            vault::signing::signer_for(device, &key)
+        }),
+    };
+    let signer = match signer {
+        Ok(signer) => signer,
+        Err(e) => {
+            eprintln!("Failed to read the key for '{}': {}", device, e);
+            return false;
+        }
+    };
+
+    let mut text = fs::read_to_string(&path).unwrap_or_default();
+    if vault::signing::parse_trusted(&text)
+        .iter()
+        .any(|existing| existing.key == signer.key)
+    {
+        println!("That key is already trusted.");
+        return true;
+    }
+    text.push_str(&signer.to_line());
+    text.push('\n');
+    if let Err(e) = fs::write(&path, text) {
+        eprintln!(
+            "Failed to write {}: {}",
+            vault::signing::TRUSTED_SIGNERS_FILE,
+            e
+        );
+        return false;
+    }
+
+    match commit_vault_changes(config, &[], "trust") {
+        Ok(_) => {
+            println!("Commits signed by '{}' are now trusted.", device);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to commit the trusted key: {}", e);
+            false
+        }
+    }
+}
+
+/// Lists the members who can open the vault key
+fn list_members(config: &Config) -> bool {
+    let members = vault::members::load(&config.note_dir).map_err(|e| e.to_string());
+    output::report("member list", members, |members| {
+        if members.is_empty() {
+            println!("The vault has no members yet. Add yourself with `prive member add <name>`.");
+        }
+        for member in members {
+            println!("{}  {}", member.name, member.recipient);
+        }
+    })
+}
+
+/// Prints the age recipient of this device's member key, creating the key if needed
+fn show_member_key() -> bool {
+    let identity = vault::members::load_identity().and_then(|identity| match identity {
+        Some(identity) => Ok(identity),
+        None => vault::members::create_identity(),
+    });
+    let recipient = identity
+        .map(|identity| identity.to_public().to_string())
+        .map_err(|e| format!("Failed to read your member key: {}", e));
+    output::report("member key", recipient, |recipient| {
+        println!("{}", recipient);
+        println!(
+            "Ask a member of the vault to run `prive member add <name> {}`.",
+            recipient
+        );
+    })
+}
+
+/// A folder of a shared vault and the members who can read its notes
+#[derive(Serialize)]
+struct FolderAccess {
+    folder: String,
+    members: Vec<String>,
+    /// Whether the folder's key is wrapped for exactly these members
+    applied: bool,
+}
+
+/// Lists each folder's readers from the vault policy, starting with the vault root
+fn show_policy(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let access = (|| -> Result<Vec<FolderAccess>, Box<dyn Error>> {
+        let members = vault::members::load(note_dir)?;
+        let policy = vault::policy::load(note_dir, &members)?;
+        let names = |members: &[vault::members::Member]| {
+            let mut names: Vec<String> = members.iter().map(|m| m.name.clone()).collect();
+            names.sort();
+            names
+        };
+
+        let mut access = vec![FolderAccess {
+            folder: String::new(),
+            members: names(&members),
+            applied: true,
+        }];
+        for rule in &policy.rules {
+            let scope = Path::new(note_dir).join(&rule.folder);
+            let readers = vault::members::load(&scope.to_string_lossy())?;
+            let allowed = names(&policy.recipients(rule, &members));
+            access.push(FolderAccess {
+                folder: rule.folder.clone(),
+                applied: scope.join(vault::members::WRAPPED_KEY_FILE).exists()
+                    && names(&readers) == allowed,
+                members: allowed,
+            });
+        }
+        Ok(access)
+    })()
+    .map_err(|e| e.to_string());
+
+    output::report("policy show", access, |access| {
+        for folder in access {
+            let name = if folder.folder.is_empty() {
+                "(root)"
+            } else {
+                folder.folder.as_str()
+            };
+            let pending = if folder.applied {
+                ""
+            } else {
+                "  (not applied)"
+            };
+            println!("{}  {}{}", name, folder.members.join(" "), pending);
+        }
+    })
+}
+
+/// Applies the vault policy and commits the folder keys and re-encrypted notes
+fn apply_policy(config: &Config) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let applied = secured_note_files(note_dir)
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|notes| vault::policy::apply(note_dir, &notes));
+    let changed = match applied {
+        Ok(changed) => changed,
+        Err(e) => {
+            eprintln!("Failed to apply {}: {}", vault::policy::POLICY_FILE, e);
+            return false;
+        }
+    };
+
+    let files: Vec<&str> = changed.iter().map(String::as_str).collect();
+    match commit_vault_changes(config, &files, "policy") {
+        Ok(_) => {
+            println!(
+                "{} applied; {} note(s) were re-encrypted.",
+                vault::policy::POLICY_FILE,
+                files
+                    .iter()
+                    .filter(|file| file.ends_with(".secured"))
+                    .count()
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to commit and push the policy: {}", e);
+            false
+        }
+    }
+}
+
+/// Wraps the vault key for a new member, defaulting to this device's member key
+fn add_member(config: &Config, name: &str, recipient: Option<&str>) -> bool {
+    let note_dir = config.note_dir.as_str();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        eprintln!("Error: member names cannot be empty or contain spaces.");
+        return false;
+    }
+
+    let added = (|| -> Result<(), Box<dyn Error>> {
+        let recipient = match recipient {
+            Some(recipient) => vault::members::parse_recipient(recipient)?,
+            None => match vault::members::load_identity()? {
+                Some(identity) => identity.to_public(),
+                None => vault::members::create_identity()?.to_public(),
+            },
+        }
+        .to_string();
+
+        // Never wrap the vault key for a member list nobody in the vault wrote
+        vault::manifest::check_access(
+            note_dir,
+            &[
+                vault::members::MEMBERS_FILE,
+                vault::members::WRAPPED_KEY_FILE,
+            ],
+        )?;
+        let mut members = vault::members::load(note_dir)?;
+        if let Some(existing) = members
+            .iter()
+            .find(|member| member.name == name || member.recipient == recipient)
+        {
+            return Err(format!(
+                "'{}' is already a member with that name or key.",
+                existing.name
+            )
+            .into());
+        }
+        let vault_key = if vault::key::exists(note_dir) {
+            vault::key::vault_key(note_dir)?
+        } else {
+            vault::key::create(note_dir)?
+        };
+
+        members.push(vault::members::Member {
+            name: name.to_string(),
+            recipient,
+        });
+        vault::members::save(note_dir, &members)?;
+        vault::members::wrap(note_dir, &vault_key, &members)?;
+        commit_vault_changes(config, &[], "member")?;
+        Ok(())
+    })();
+    match added {
+        Ok(_) => {
+            println!("'{}' can open the vault once this change is synced.", name);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to add '{}': {}", name, e);
+            false
+        }
+    }
+}
+
+/// Removes a member and rotates the vault key
+///
+/// Every vault note is re-encrypted with a new vault key wrapped only for the
+/// remaining members. Earlier revisions in the git history stay readable with
+/// the old key, which the removed member may still have.
+fn remove_member(config: &Config, name: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let checked = vault::manifest::check_access(
+        note_dir,
+        &[
+            vault::members::MEMBERS_FILE,
+            vault::members::WRAPPED_KEY_FILE,
+        ],
+    );
+    if let Err(e) = checked {
+        eprintln!("{}", e);
+        return false;
+    }
+    let mut members = match vault::members::load(note_dir) {
+        Ok(members) => members,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let Some(index) = members.iter().position(|member| member.name == name) else {
+        eprintln!("'{}' is not a member of this vault.", name);
+        return false;
+    };
+    let named = vault::policy::load(note_dir, &members).map(|policy| {
+        policy
+            .rules
+            .into_iter()
+            .filter(|rule| rule.members.iter().any(|member| member == name))
+            .map(|rule| rule.folder)
+            .collect::<Vec<_>>()
+    });
+    match named {
+        Ok(folders) if !folders.is_empty() => {
+            eprintln!(
+                "'{}' can read {} in {}; remove them there and run `prive policy apply` first.",
+                name,
+                folders.join(", "),
+                vault::policy::POLICY_FILE
+            );
+            return false;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    }
+    members.remove(index);
+    if members.is_empty() {
+        eprintln!(
+            "'{}' is the last member; add another member before removing them.",
+            name
+        );
+        return false;
+    }
+
+    match rotate_vault_key(note_dir, &members) {
+        Ok(mut files) => {
+            files.extend(
+                vault::metadata_files(note_dir)
+                    .iter()
+                    .map(|file| file.to_string()),
+            );
+            let files: Vec<&str> = files.iter().map(String::as_str).collect();
+            if let Err(e) = commit_vault_changes(config, &files, "member") {
+                eprintln!("Failed to commit and push the new vault key: {}", e);
+                return false;
             }
+            println!(
+                "'{}' was removed and the vault key rotated; {} note(s) were re-encrypted.",
+                name,
+                files
+                    .iter()
+                    .filter(|file| file.ends_with(".secured"))
+                    .count()
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to remove '{}': {}", name, e);
+            false
+        }
+    }
+}
+
+/// Re-encrypts vault notes with a new vault key wrapped for the given members
+///
+/// Notes in folders with their own key are left alone. Returns the notes that were re-encrypted. Nothing is written until every
+/// note has been re-encrypted in memory, and the new key is saved before any note.
+fn rotate_vault_key(
+    note_dir: &str,
+    members: &[vault::members::Member],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let old_key = vault::key::note_key(note_dir)?;
+    let new_key = crypto::VaultKey::generate();
+    let new_note_key =
+        vault::key::NoteKey::Vault(crypto::VaultKey::from_bytes(new_key.as_bytes())?);
+
+    let mut reencrypted = Vec::new();
+    for file in secured_note_files(note_dir)? {
+        if vault::policy::scope(note_dir, &file).is_some() {
+            continue;
+        }
+        let data = fs::read(Path::new(note_dir).join(&file))?;
+        if !crypto::is_prive_format(&data)
+            || !matches!(crypto::key_mode(&data), Ok(crypto::KeyMode::Vault))
+        {
+            continue;
+        }
+        let plaintext = old_key
+            .decrypt(&data)
+            .map_err(|e| format!("Failed to decrypt '{}': {}", file, e))?;
+        reencrypted.push((file, new_note_key.encrypt(&plaintext)?));
+    }
+
+    // Whoever was removed may know the master passphrase, so it is replaced too
+    let passphrase = if vault::key::key_path(note_dir).exists() {
+        Some(prompt::new_password(
+            "The vault key is also protected by a master passphrase. Choose a new one:",
+        )?)
+    } else {
+        None
+    };
+
+    // Persist the new key before any note depends on it, so a crash never strands a note
+    vault::key::bind_rotation(note_dir, &old_key, &new_key)?;
+    vault::members::save(note_dir, members)?;
+    vault::members::wrap(note_dir, &new_key, members)?;
+    if let Some(passphrase) = passphrase {
+        vault::key::store(note_dir, &new_key, &passphrase)?;
+    }
+    for (file, data) in &reencrypted {
+        workspace::write_atomic(&Path::new(note_dir).join(file), data)?;
+    }
+    vault::manifest::rekey(note_dir, &old_key, &new_note_key)?;
+    vault::key::replace(note_dir, &new_key)?;
+    Ok(reencrypted.into_iter().map(|(file, _)| file).collect())
+}
+
+/// Returns the name of a note's secured file, accepting names with or without the suffix
+fn secured_file_name(note: &str) -> String {
+    if note.ends_with(".secured") {
+        note.to_string()
+    } else {
+        format!("{}.secured", note)
+    }
+}
+
+/// Lists the commits that changed a note
+fn show_note_history(config: &Config, note: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let revisions = checked_file_name(note).and_then(|file_name| {
+        vault::Repo::open(note_dir)
+            .and_then(|repo| repo.history(&file_name))
+            .map_err(|e| format!("Failed to read the history of '{}': {}", note, e))
+    });
+
+    output::report("history", revisions, |revisions| {
+        if revisions.is_empty() {
+            println!("No history found for '{}'.", note);
+        }
+        for revision in revisions {
+            println!(
+                "{}  {}  {}",
+                revision.id,
+                vault::repo::format_time(revision.time),
+                revision.summary
+            );
+        }
+    })
+}
+
+/// Decrypts and prints a note as it was at a revision
+fn show_note_revision(config: &Config, note: &str, rev: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let file_name = match checked_file_name(note) {
+        Ok(file_name) => file_name,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return false;
+        }
+    };
+
+    let plaintext = vault::Repo::open(note_dir).and_then(|repo| {
+        let data = repo.file_at(&file_name, rev)?;
+        // Old revisions may predate the current format
+        if crypto::is_prive_format(&data) {
+            decrypt_revision(note_dir, &repo, &file_name, rev, &data)
+                .map(|(plaintext, _)| plaintext)
+        } else {
+            workspace::WorkingCopy::create(&file_name, &data)
+                .and_then(|copy| crypto::decrypt_legacy_file(copy.path()))
+        }
+    });
+    match plaintext {
+        Ok(plaintext) => match io::stdout().write_all(&plaintext) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Failed to print the note: {}", e);
+                false
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to decrypt '{}' at {}: {}", note, rev, e);
+            false
+        }
+    }
+}
+
+/// Decrypts a note as it was at a revision, falling back to the vault key of that time
+fn decrypt_revision(
+    note_dir: &str,
+    repo: &vault::Repo,
+    file_name: &str,
+    rev: &str,
+    data: &[u8],
+) -> Result<OpenedNote, Box<dyn Error>> {
+    if crypto::key_mode(data)? != crypto::KeyMode::Vault {
+        return decrypt_note_data(note_dir, file_name, data);
+    }
+    let current = vault::policy::note_key(note_dir, file_name)
+        .and_then(|key| key.decrypt(data).map(|plaintext| (plaintext, key)));
+    let (plaintext, key) = match current {
+        Ok(opened) => opened,
+        // The vault or folder key may have been rotated since
+        Err(_) => {
+            let key = vault::key::NoteKey::Vault(vault::policy::note_key_at(
+                note_dir, repo, file_name, rev,
+            )?);
+            (key.decrypt(data)?, key)
+        }
+    };
+    Ok((plaintext, Some(key)))
+}
+
+/// Makes an earlier revision of a note current and commits it
+///
+/// Vault notes are re-encrypted with the key that protects them today, so a
+/// revision from before a key rotation is not restored under a retired key.
+fn restore_note(config: &Config, note: &str, rev: &str) -> bool {
+    let note_dir = config.note_dir.as_str();
+    let file_name = match checked_file_name(note) {
+        Ok(file_name) => file_name,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return false;
+        }
+    };
+    let file_path = format!("{}/{}", note_dir, file_name);
+
+    let restored = vault::Repo::open(note_dir).and_then(|repo| {
+        let data = repo.file_at(&file_name, rev)?;
+        // Legacy revisions are restored as they were and upgraded on their next save
+        if !crypto::is_prive_format(&data) {
+            return Ok(data);
+        }
+        let (plaintext, key) = decrypt_revision(note_dir, &repo, &file_name, rev, &data)?;
+        match key {
+            Some(key) if key.mode() == crypto::KeyMode::Password => key.encrypt(&plaintext),
+            _ => vault_note_key(note_dir, &file_name)?.encrypt(&plaintext),
+        }
+    });
+    let data = match restored {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read '{}' at {}: {}", note, rev, e);
+            return false;
+        }
+    };
+
+    if let Err(e) = create_note_folder(Path::new(&file_path))
+        .and_then(|_| workspace::write_atomic(Path::new(&file_path), &data))
+    {
+        eprintln!("Failed to restore '{}': {}", note, e);
+        return false;
+    }
+    match commit_vault_changes(config, &[&file_name], "restore") {
+        Ok(_) => {
+            println!("Restored '{}' to {}.", note, rev);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to commit and push the restored note: {}", e);
+            false
+        }
+    }
+}
+
+/// Returns a key for re-encrypting a note, matching how its secured file is protected today
+fn existing_note_key(
+    note_dir: &str,
+    secured_file_path: &str,
+) -> Result<vault::key::NoteKey, Box<dyn Error>> {
+    let existing_mode = fs::read(secured_file_path)
+        .ok()
+        .filter(|data| crypto::is_prive_format(data))
+        .and_then(|data| crypto::key_mode(&data).ok());
+    match existing_mode {
+        Some(crypto::KeyMode::Vault) => vault_note_key(note_dir, secured_file_path),
+        Some(crypto::KeyMode::Password) => {
+            prompt::new_password("Enter a password to encrypt the note:")
+                .map(vault::key::NoteKey::Password)
+        }
+        None => choose_note_key(note_dir, secured_file_path),
+    }
+}
+
+/// Offers to re-encrypt, diff or wipe plaintext left behind by an interrupted session
+fn recover_orphaned_notes(config: &Config) {
+    let note_dir = config.note_dir.as_str();
+    let orphans = workspace::find_orphans(note_dir);
+    if orphans.is_empty() {
+        return;
+    }
+
+    println!(
+        "Found {} decrypted note(s) left behind by an interrupted session.",
+        orphans.len()
+    );
+    for orphan in &orphans {
+        let secured_file_path = format!("{}/{}.secured", note_dir, orphan.note_name);
+        loop {
+            println!(
+                "Plaintext of '{}' found at {}",
+                orphan.note_name,
+                orphan.path.display()
+            );
+            println!("1. Re-encrypt it into {}.secured", orphan.note_name);
+            println!("2. Show differences from the encrypted note");
+            println!("3. Securely wipe the plaintext");
+            println!("4. Leave it for now");
+
+            let mut choice = String::new();
+            if io::stdin().read_line(&mut choice).is_err() {
+                println!("Error reading input.");
+                return;
+            }
+            match choice.trim() {
+                "1" => {
+                    if Path::new(&secured_file_path).exists() {
+                        println!("This replaces the current encrypted note. Continue? (yes/no)");
+                        let mut confirm = String::new();
+                        if io::stdin().read_line(&mut confirm).is_err()
+                            || !confirm.trim().eq_ignore_ascii_case("yes")
+                        {
+                            continue;
+                        }
+                    }
+                    match reencrypt_orphan(config, orphan, &secured_file_path) {
+                        Ok(_) => {
+                            println!("'{}' re-encrypted and committed.", orphan.note_name);
+                            break;
+                        }
+                        Err(e) => println!("Failed to re-encrypt '{}': {}", orphan.note_name, e),
+                    }
+                }
+                "2" => {
+                    if let Err(e) = diff_orphan(config, orphan, &secured_file_path) {
+                        println!("Failed to compare '{}': {}", orphan.note_name, e);
+                    }
+                }
+                "3" => {
+                    println!(
+                        "The plaintext cannot be recovered afterwards. Type 'wipe' to confirm:"
+                    );
+                    let mut confirm = String::new();
+                    if io::stdin().read_line(&mut confirm).is_ok() && confirm.trim() == "wipe" {
+                        match workspace::wipe_file(&orphan.path) {
+                            Ok(_) => {
+                                println!("Plaintext wiped.");
+                                break;
+                            }
+                            Err(e) => println!("Failed to wipe the plaintext: {}", e),
+                        }
+                    }
+                }
+                "4" => break,
+                _ => println!("Invalid choice, please enter a number between 1 and 4."),
+            }
+        }
+    }
+}
+
+/// Encrypts a leftover plaintext into its secured file, commits it and wipes the plaintext
+fn reencrypt_orphan(
+    config: &Config,
+    orphan: &workspace::Orphan,
+    secured_file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let plaintext = zeroize::Zeroizing::new(fs::read(&orphan.path)?);
+    let key = existing_note_key(&config.note_dir, secured_file_path)?;
+    create_note_folder(Path::new(secured_file_path))?;
+    key.encrypt_file(Path::new(secured_file_path), &plaintext)?;
+    workspace::wipe_file(&orphan.path)?;
+
+    commit_vault_changes(config, &[secured_file_path], "recover")?;
+    Ok(())
+}
+
+/// Prints a unified diff between the encrypted note and its leftover plaintext
+fn diff_orphan(
+    config: &Config,
+    orphan: &workspace::Orphan,
+    secured_file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let note_dir = config.note_dir.as_str();
+    let leftover = zeroize::Zeroizing::new(fs::read(&orphan.path)?);
+    if !Path::new(secured_file_path).exists() {
+        println!(
+            "There is no encrypted version of '{}' yet.",
+            orphan.note_name
+        );
+        return Ok(());
+    }
+
+    let (current, _) = read_secured_note(note_dir, Path::new(secured_file_path))?;
+    let current = String::from_utf8_lossy(&current);
+    let leftover = String::from_utf8_lossy(&leftover);
+    if current == leftover {
+        println!("The plaintext matches the encrypted note.");
+    } else {
+        print!("{}", diffy::create_patch(&current, &leftover));
+    }
+    Ok(())
+}
+
+/// Re-encrypts notes whose key derivation costs are below the target
+fn rekdf_notes(config: &Config, notes: &[String], target: &crypto::KdfParams, force: bool) -> bool {
+    if let Err(e) = target.validate() {
+        eprintln!("Error: {}", e);
+        return false;
+    }
+
+    let note_dir = config.note_dir.as_str();
+    if env::set_current_dir(note_dir).is_err() {
+        eprintln!("Failed to change directory to {}", note_dir);
+        return false;
+    }
+
+    let secured_files = if notes.is_empty() {
+        match secured_note_files(note_dir) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Failed to list secured notes: {}", e);
+                return false;
+            }
+        }
+    } else {
+        match notes.iter().map(|note| checked_file_name(note)).collect() {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return false;
+            }
+        }
+    };
+
+    let mut updated = Vec::new();
+    let mut failed = false;
+    for file in &secured_files {
+        let file_path = format!("{}/{}", note_dir, file);
+        let data = match fs::read(&file_path) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Skipping '{}': {}", file, e);
+                failed = true;
+                continue;
+            }
+        };
+        if !crypto::is_prive_format(&data) {
+            println!(
+                "Skipping '{}': legacy notes must be opened and saved once first.",
+                file
+            );
+            continue;
+        }
+        // Vault notes are covered by re-wrapping the vault key below
+        if matches!(crypto::key_mode(&data), Ok(crypto::KeyMode::Vault)) {
+            continue;
+        }
+
+        let current = match crypto::kdf_params(&data) {
+            Ok(current) => current,
+            Err(e) => {
+                eprintln!("Skipping '{}': {}", file, e);
+                failed = true;
+                continue;
+            }
+        };
+        if !force && current.at_least(target) {
+            println!("'{}' already uses {}.", file, current);
+            continue;
+        }
+
+        println!("Re-encrypting '{}' ({} -> {})", file, current, target);
+        let password = match prompt::password(&format!("Enter the password for {}:", file)) {
+            Ok(password) => password,
+            Err(e) => {
+                eprintln!("Error reading password: {}", e);
+                return false;
+            }
+        };
+        let reencrypted = crypto::decrypt(&data, &password)
+            .and_then(|plaintext| crypto::encrypt_with_params(&plaintext, &password, target));
+        match reencrypted {
+            Ok(reencrypted) => {
+                if let Err(e) = workspace::write_atomic(Path::new(&file_path), &reencrypted) {
+                    eprintln!("Failed to write '{}': {}", file, e);
+                    failed = true;
+                    continue;
+                }
+                updated.push(file.as_str());
+            }
+            Err(e) => {
+                eprintln!("Skipping '{}': {}", file, e);
+                failed = true;
+            }
+        }
+    }
+
+    if vault::key::key_path(note_dir).exists() {
+        match vault::key::kdf_params(note_dir) {
+            Ok(current) if force || !current.at_least(target) => {
+                println!("Re-wrapping the vault key ({} -> {})", current, target);
+                let rewrapped = prompt::password("Enter the vault master passphrase:")
+                    .map_err(Box::<dyn Error>::from)
+                    .and_then(|passphrase| vault::key::rewrap(note_dir, &passphrase, target));
+                match rewrapped {
+                    Ok(_) => updated.push(vault::key::VAULT_KEY_FILE),
+                    Err(e) => {
+                        eprintln!("Failed to re-wrap the vault key: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            Ok(current) => println!("The vault key already uses {}.", current),
+            Err(e) => {
+                eprintln!("Failed to read the vault key: {}", e);
+                failed = true;
+            }
+        }
+    }
+
+    if !updated.is_empty() {
+        if let Err(e) = commit_vault_changes(config, &updated, "rekdf") {
+            eprintln!("Failed to commit and push the re-encrypted notes: {}", e);
+            failed = true;
+        }
+        println!("{} file(s) re-encrypted with {}.", updated.len(), target);
+    } else if !failed {
+        println!("No notes needed re-encrypting.");
+    }
+    !failed
+}
+
+/// Unlocks the vault key and hands it to a background agent
+fn start_agent(config: &Config, timeout: u64) -> bool {
+    let note_dir = config.note_dir.as_str();
+    if agent::is_running(note_dir) {
+        println!("The agent is already running. Use `prive lock` to stop it.");
+        return true;
+    }
+
+    let started =
+        vault::key::unlock(note_dir).and_then(|key| agent::start(note_dir, &key, timeout));
+    match started {
+        Ok(_) => {
+            println!(
+                "Vault unlocked. The agent forgets the key after {} seconds of inactivity.",
+                timeout
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to start the agent: {}", e);
+            false
+        }
+    }
+}
+
+/// A named vault as listed by `prive vault list`
+#[derive(Serialize)]
+struct VaultEntry {
+    name: String,
+    default: bool,
+    #[serde(flatten)]
+    profile: config::VaultProfile,
+}
+
+/// Runs a `prive vault` subcommand against the config file
+fn manage_vaults(config_path: Option<&Path>, command: VaultCommands) -> bool {
+    let path = match config::file_path(config_path) {
+        Ok(path) => path,
+        Err(e) => return output::report::<()>("vault", Err(e), |_| {}),
+    };
+
+    match command {
+        VaultCommands::List => {
+            let entries = config::vaults(config_path).map(|(vaults, default)| {
+                vaults
+                    .into_iter()
+                    .map(|(name, profile)| VaultEntry {
+                        default: default.as_deref() == Some(name.as_str()),
+                        name,
+                        profile,
+                    })
+                    .collect::<Vec<_>>()
+            });
+            output::report("vault list", entries, |entries| {
+                if entries.is_empty() {
+                    println!("No named vaults. Add one with `prive vault add NAME`.");
+                }
+                for entry in entries {
+                    let marker = if entry.default { "*" } else { " " };
+                    match &entry.profile.remote {
+                        Some(remote) => println!(
+                            "{} {}  {}  {}",
+                            marker, entry.name, entry.profile.path, remote
+                        ),
+                        None => println!("{} {}  {}", marker, entry.name, entry.profile.path),
+                    }
+                }
+            })
+        }
+        VaultCommands::Add {
+            name,
+            path: vault_path,
+            remote,
+            branch,
+            agent_timeout,
+        } => {
+            let profile = config::VaultProfile {
+                path: vault_path.unwrap_or_else(|| format!("~/.prive-note-{}", name)),
+                remote,
+                branch,
+                agent_timeout,
+            };
+            let added = config::add_vault(&path, &name, &profile);
+            output::report("vault add", added, |_| {
+                println!(
+                    "Added vault '{}' at {}. Set it up with `prive --vault {} init`.",
+                    name, profile.path, name
+                )
+            })
+        }
+        VaultCommands::Remove { name } => {
+            let removed = config::remove_vault(&path, &name);
+            output::report("vault remove", removed, |_| {
+                println!(
+                    "Removed vault '{}' from {}; its directory was left in place.",
+                    name,
+                    path.display()
+                )
+            })
+        }
+        VaultCommands::Default { name, clear } => {
+            let result = match (name, clear) {
+                (None, false) => config::vaults(config_path).map(|(_, default)| default),
+                (name, _) => config::set_default_vault(&path, name.as_deref()).map(|_| name),
+            };
+            output::report("vault default", result, |default| match default {
+                Some(name) => println!("The default vault is '{}'.", name),
+                None => println!(
+                    "The default vault is the unnamed one from the top of the config file."
+                ),
+            })
         }
     }
 }
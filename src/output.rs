@@ -0,0 +1,69 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Version of the JSON documents printed with `--json`
+///
+/// Fields may be added within a version; it only changes when existing fields
+/// are renamed, removed or change meaning.
+pub const JSON_VERSION: u32 = 1;
+
+/// Whether results are printed as JSON documents instead of text
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Switches command results to JSON output for the rest of the process
+pub fn enable_json() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+/// Returns true when command results are printed as JSON documents
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Envelope around every JSON result so scripts can check the version first
+#[derive(Serialize)]
+struct Document<'a, T: Serialize> {
+    version: u32,
+    command: &'a str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Prints a command's result, as a JSON document with `--json` and through `print` otherwise
+///
+/// Errors go to stderr in text mode and into the document's `error` field in
+/// JSON mode. Returns false when the command failed.
+pub fn report<T: Serialize>(
+    command: &str,
+    result: Result<T, String>,
+    print: impl FnOnce(&T),
+) -> bool {
+    let ok = result.is_ok();
+    if !json() {
+        match result {
+            Ok(value) => print(&value),
+            Err(e) => eprintln!("{}", e),
+        }
+        return ok;
+    }
+
+    let (result, error) = match result {
+        Ok(value) => (Some(value), None),
+        Err(e) => (None, Some(e)),
+    };
+    let document = Document {
+        version: JSON_VERSION,
+        command,
+        ok,
+        result,
+        error,
+    };
+    match serde_json::to_string(&document) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Failed to format the result as JSON: {}", e),
+    }
+    ok
+}
@@ -0,0 +1,50 @@
+use std::error::Error;
+use std::io::{self, Read};
+use zeroize::Zeroizing;
+
+/// Size of the chunks stdin is read in
+const STDIN_CHUNK: usize = 4096;
+
+/// Prompts for a password on the terminal with echo disabled
+pub fn password(prompt: &str) -> io::Result<Zeroizing<String>> {
+    rpassword::prompt_password(format!("{} ", prompt)).map(Zeroizing::new)
+}
+
+/// Reads all of stdin into memory that is wiped when dropped
+///
+/// The buffer is grown by hand so reallocating it never leaves an unwiped copy
+/// of the input behind.
+pub fn read_stdin() -> io::Result<Zeroizing<Vec<u8>>> {
+    let mut input = Zeroizing::new(Vec::with_capacity(STDIN_CHUNK));
+    let mut chunk = Zeroizing::new([0u8; STDIN_CHUNK]);
+    let mut stdin = io::stdin().lock();
+
+    loop {
+        let read = match stdin.read(chunk.as_mut()) {
+            Ok(0) => return Ok(input),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if input.len() + read > input.capacity() {
+            let mut grown = Zeroizing::new(Vec::with_capacity((input.len() + read) * 2));
+            grown.extend_from_slice(&input);
+            input = grown;
+        }
+        input.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Prompts for a new password twice and makes sure both entries match
+pub fn new_password(prompt: &str) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    let first = password(prompt)?;
+    if first.is_empty() {
+        return Err("Password cannot be empty.".into());
+    }
+
+    let second = password("Confirm the password:")?;
+    if *first != *second {
+        return Err("Passwords do not match.".into());
+    }
+    Ok(first)
+}
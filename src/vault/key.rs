@@ -0,0 +1,223 @@
+use super::members;
+use crate::agent;
+use crate::crypto::{self, KdfParams, KeyMode, VaultKey};
+use crate::{prompt, workspace};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// File in the vault holding the vault key, encrypted with the master passphrase
+pub const VAULT_KEY_FILE: &str = "vault.key";
+
+/// File in the vault binding the vault key to the one it replaced
+///
+/// Holds the new key's fingerprint encrypted with the old key, so only
+/// someone who held the old key can introduce a new one.
+pub const ROTATION_FILE: &str = "vault.key.rotation";
+
+/// Vault key unlocked earlier in this process, so a single run prompts at most once
+static UNLOCKED: Mutex<Option<VaultKey>> = Mutex::new(None);
+
+/// Remembers an unlocked vault key for the rest of this process
+fn remember(vault_key: &VaultKey) -> Result<(), Box<dyn Error>> {
+    let copy = VaultKey::from_bytes(vault_key.as_bytes())?;
+    if let Ok(mut unlocked) = UNLOCKED.lock() {
+        *unlocked = Some(copy);
+    }
+    Ok(())
+}
+
+/// Returns a copy of the vault key unlocked earlier in this process
+fn remembered() -> Option<VaultKey> {
+    let unlocked = UNLOCKED.lock().ok()?;
+    let vault_key = unlocked.as_ref()?;
+    VaultKey::from_bytes(vault_key.as_bytes()).ok()
+}
+
+/// Secret used to encrypt or decrypt a single note
+pub enum NoteKey {
+    /// The note's own password
+    Password(Zeroizing<String>),
+    /// The unlocked vault key
+    Vault(VaultKey),
+    /// The vault key held by the `prive agent` serving the vault directory
+    Agent(String),
+}
+
+impl NoteKey {
+    pub fn mode(&self) -> KeyMode {
+        match self {
+            NoteKey::Password(_) => KeyMode::Password,
+            NoteKey::Vault(_) | NoteKey::Agent(_) => KeyMode::Vault,
+        }
+    }
+
+    /// Encrypts plaintext into the contents of a `.secured` file
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            NoteKey::Password(password) => crypto::encrypt(plaintext, password),
+            NoteKey::Vault(vault_key) => crypto::encrypt_with_vault_key(plaintext, vault_key),
+            NoteKey::Agent(note_dir) => agent::encrypt(note_dir, plaintext),
+        }
+    }
+
+    /// Decrypts the contents of a `.secured` file
+    pub fn decrypt(&self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+        match self {
+            NoteKey::Password(password) => crypto::decrypt(data, password),
+            NoteKey::Vault(vault_key) => crypto::decrypt_with_vault_key(data, vault_key),
+            NoteKey::Agent(note_dir) => agent::decrypt(note_dir, data),
+        }
+    }
+
+    /// Encrypts plaintext and writes it to a `.secured` file
+    pub fn encrypt_file(&self, path: &Path, plaintext: &[u8]) -> Result<(), Box<dyn Error>> {
+        let data = self.encrypt(plaintext)?;
+        workspace::write_atomic(path, &data)?;
+        Ok(())
+    }
+}
+
+/// Makes this process forget the vault key and locks the agent holding it
+pub fn forget(note_dir: &str) {
+    if let Ok(mut unlocked) = UNLOCKED.lock() {
+        *unlocked = None;
+    }
+    let _ = agent::lock(note_dir);
+}
+
+/// Switches this process to a new vault key after the old one was rotated
+pub fn replace(note_dir: &str, vault_key: &VaultKey) -> Result<(), Box<dyn Error>> {
+    forget(note_dir);
+    remember(vault_key)
+}
+
+/// Returns the path of the encrypted vault key file
+pub fn key_path(note_dir: &str) -> PathBuf {
+    Path::new(note_dir).join(VAULT_KEY_FILE)
+}
+
+/// Returns true if the vault has a vault key, wrapped with the master passphrase or for members
+pub fn exists(note_dir: &str) -> bool {
+    key_path(note_dir).exists() || members::is_shared(note_dir)
+}
+
+/// Writes the vault key encrypted with the master passphrase
+pub fn store(note_dir: &str, vault_key: &VaultKey, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    let data = crypto::encrypt(vault_key.as_bytes(), passphrase)?;
+    // The vault key file is the only copy of the key; never leave it half written
+    workspace::write_atomic(&key_path(note_dir), &data)?;
+    Ok(())
+}
+
+/// Generates a new vault key and stores it encrypted with the master passphrase
+pub fn create(note_dir: &str) -> Result<VaultKey, Box<dyn Error>> {
+    if exists(note_dir) {
+        return Err("The vault key already exists.".into());
+    }
+
+    println!("Setting up the vault. The master passphrase unlocks every vault note.");
+    let passphrase = prompt::new_password("Choose a master passphrase:")?;
+    let vault_key = VaultKey::generate();
+    store(note_dir, &vault_key, &passphrase)?;
+    remember(&vault_key)?;
+    Ok(vault_key)
+}
+
+/// Decrypts the vault key with the given master passphrase
+pub fn unlock_with(note_dir: &str, passphrase: &str) -> Result<VaultKey, Box<dyn Error>> {
+    let data = fs::read(key_path(note_dir))
+        .map_err(|e| format!("Failed to read {}: {}", VAULT_KEY_FILE, e))?;
+    let bytes = crypto::decrypt(&data, passphrase).map_err(|_| "Incorrect master passphrase.")?;
+    VaultKey::from_bytes(&bytes)
+}
+
+/// Returns the vault note key, using the agent when it holds the unlocked vault key
+pub fn note_key(note_dir: &str) -> Result<NoteKey, Box<dyn Error>> {
+    if agent::is_running(note_dir) {
+        return Ok(NoteKey::Agent(note_dir.to_string()));
+    }
+    if let Some(vault_key) = remembered() {
+        return Ok(NoteKey::Vault(vault_key));
+    }
+    let vault_key = unlock(note_dir)?;
+    remember(&vault_key)?;
+    Ok(NoteKey::Vault(vault_key))
+}
+
+/// Returns the vault key itself, prompting even when an agent holds it
+pub fn vault_key(note_dir: &str) -> Result<VaultKey, Box<dyn Error>> {
+    if let Some(vault_key) = remembered() {
+        return Ok(vault_key);
+    }
+    let vault_key = unlock(note_dir)?;
+    remember(&vault_key)?;
+    Ok(vault_key)
+}
+
+/// Unlocks the vault key with this device's member key, or else the master passphrase
+pub fn unlock(note_dir: &str) -> Result<VaultKey, Box<dyn Error>> {
+    if members::is_shared(note_dir) {
+        let data = fs::read(Path::new(note_dir).join(members::WRAPPED_KEY_FILE))?;
+        match members::unwrap(&data) {
+            Ok(Some(vault_key)) => return Ok(vault_key),
+            Ok(None) if !key_path(note_dir).exists() => {
+                return Err(
+                    "This device has no member key; create one with `prive member add`.".into(),
+                )
+            }
+            Err(e) if !key_path(note_dir).exists() => return Err(e),
+            Err(e) => eprintln!("{} Trying the master passphrase instead.", e),
+            Ok(None) => {}
+        }
+    }
+    if !key_path(note_dir).exists() {
+        return Err("The vault has no master passphrase yet.".into());
+    }
+    let passphrase = prompt::password("Enter the vault master passphrase:")?;
+    unlock_with(note_dir, &passphrase)
+}
+
+/// Returns the hex SHA-256 of a vault key, which names it without revealing it
+fn fingerprint(vault_key: &VaultKey) -> String {
+    Sha256::digest(vault_key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Records that the old vault key handed over to a new one
+pub fn bind_rotation(note_dir: &str, old: &NoteKey, new: &VaultKey) -> Result<(), Box<dyn Error>> {
+    let record = old.encrypt(fingerprint(new).as_bytes())?;
+    workspace::write_atomic(&Path::new(note_dir).join(ROTATION_FILE), &record)?;
+    Ok(())
+}
+
+/// Returns true if a rotation record shows the old vault key handing over to a new one
+pub fn is_bound(record: &[u8], old: &NoteKey, new: &VaultKey) -> bool {
+    old.decrypt(record)
+        .is_ok_and(|fingerprint_bytes| fingerprint_bytes.as_slice() == fingerprint(new).as_bytes())
+}
+
+/// Returns true if a vault key is the one behind a note key
+pub fn is_same(key: &NoteKey, vault_key: &VaultKey) -> bool {
+    // Agent keys cannot be read back, so compare by what they decrypt
+    crypto::encrypt_with_vault_key(b"prive", vault_key)
+        .is_ok_and(|probe| key.decrypt(&probe).is_ok())
+}
+
+/// Returns the key derivation costs protecting the vault key
+pub fn kdf_params(note_dir: &str) -> Result<KdfParams, Box<dyn Error>> {
+    crypto::kdf_params(&fs::read(key_path(note_dir))?)
+}
+
+/// Re-encrypts the vault key file under new key derivation costs
+pub fn rewrap(note_dir: &str, passphrase: &str, kdf: &KdfParams) -> Result<(), Box<dyn Error>> {
+    let vault_key = unlock_with(note_dir, passphrase)?;
+    let data = crypto::encrypt_with_params(vault_key.as_bytes(), passphrase, kdf)?;
+    workspace::write_atomic(&key_path(note_dir), &data)?;
+    Ok(())
+}
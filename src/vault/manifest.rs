@@ -0,0 +1,570 @@
+use super::key::{self, NoteKey};
+use super::members;
+use super::message::note_id;
+use super::repo::Repo;
+use crate::crypto::{self, VaultKey};
+use crate::{prompt, workspace};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use git2::{ObjectType, Oid};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File in the vault holding the manifest, encrypted with the vault key
+pub const MANIFEST_FILE: &str = "vault.manifest";
+
+/// Device-local record of the highest manifest version this clone has seen on the remote
+const SEEN_VERSION_FILE: &str = "prive-manifest-version";
+
+/// Device-local record of when the newest manifest this clone has seen on the remote was written
+const SEEN_TIME_FILE: &str = "prive-manifest-time";
+
+/// A manifest older than this is rewritten on the next save even if no note changed
+const REFRESH_AFTER_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// A remote whose newest manifest is older than this may be withholding newer commits
+const STALE_AFTER_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Encrypted list of every note's ciphertext hash, versioned to detect rollbacks
+///
+/// Because the manifest is encrypted with the vault key, only vault members can
+/// produce one; a remote that serves an older commit or swaps two notes'
+/// ciphertexts no longer matches it. The files deciding who can open the
+/// vault are hashed too, so nobody without the vault key can add themselves.
+/// The time it was written lets devices notice a remote that keeps serving an
+/// old, valid manifest instead of newer ones.
+/// Once the vault lists trusted signers, commits changing the manifest must
+/// also carry a trusted signature.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    /// Increases with every change to the vault
+    version: u64,
+    /// Opaque note id mapped to the SHA-256 of its `.secured` file
+    notes: BTreeMap<String, String>,
+    /// Hex key that note ids are derived with; manifests written before it existed have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id_key: Option<String>,
+    /// Path of each access file mapped to its SHA-256; manifests written before it existed have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    access: Option<BTreeMap<String, String>>,
+    /// Seconds since the Unix epoch when this version was written; manifests written before it existed have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written: Option<u64>,
+}
+
+impl Manifest {
+    /// Returns the id of a note, by its path relative to the vault root
+    fn note_id(&self, file: &str) -> String {
+        match self.id_key.as_deref().and_then(decode_hex) {
+            Some(id_key) => note_id(&id_key, file),
+            None => legacy_note_id(file),
+        }
+    }
+}
+
+/// Returns the unkeyed id older manifests list notes under
+fn legacy_note_id(file: &str) -> String {
+    match Oid::hash_object(ObjectType::Blob, file.as_bytes()) {
+        Ok(oid) => oid.to_string()[..12].to_string(),
+        Err(_) => "note".to_string(),
+    }
+}
+
+/// Decodes a hex string, returning None if it is malformed
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // An odd trailing digit fails to slice and makes the whole string invalid
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+/// Returns the hex SHA-256 of a note's ciphertext
+fn content_hash(data: &[u8]) -> String {
+    encode_hex(&Sha256::digest(data))
+}
+
+/// Encodes bytes as lowercase hex
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decrypts and parses a manifest
+fn open(data: &[u8], vault_key: &NoteKey) -> Result<Manifest, Box<dyn Error>> {
+    let plaintext = vault_key
+        .decrypt(data)
+        .map_err(|_| "The vault manifest could not be decrypted with the vault key.")?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// Returns the highest manifest version this clone has seen on the remote
+///
+/// Versions created by local commits only count once they were pushed, so
+/// commits made offline never make the remote look rolled back.
+fn seen_version(repo: &Repo) -> u64 {
+    fs::read_to_string(repo.state_path(SEEN_VERSION_FILE))
+        .ok()
+        .and_then(|version| version.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Returns when the newest manifest this clone has seen on the remote was written
+fn seen_time(repo: &Repo) -> Option<u64> {
+    fs::read_to_string(repo.state_path(SEEN_TIME_FILE))
+        .ok()
+        .and_then(|time| time.trim().parse().ok())
+}
+
+/// Records a manifest seen on the remote
+fn record_seen(repo: &Repo, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    if manifest.version > seen_version(repo) {
+        fs::write(
+            repo.state_path(SEEN_VERSION_FILE),
+            manifest.version.to_string(),
+        )?;
+    }
+    if let Some(written) = manifest.written {
+        if seen_time(repo).is_none_or(|seen| written > seen) {
+            fs::write(repo.state_path(SEEN_TIME_FILE), written.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the current time in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Warns when the newest manifest seen on the remote is so old that the remote may be frozen
+///
+/// A remote can keep serving an old but correctly encrypted manifest and so
+/// hide every later change. Devices refresh the manifest's time whenever they
+/// save, so a long silence is only expected from a vault nobody has used.
+pub fn warn_if_stale(repo: &Repo) {
+    let Some(written) = seen_time(repo) else {
+        return;
+    };
+    let age = now().saturating_sub(written);
+    if age > STALE_AFTER_SECS {
+        eprintln!(
+            "Warning: the newest vault manifest on the remote was written {} days ago. \
+             If notes were saved on other devices since, the remote may be withholding them.",
+            age / (24 * 60 * 60)
+        );
+    }
+}
+
+/// Rewrites the manifest to match the notes in the working tree
+///
+/// Vaults without a vault key have nothing to encrypt a manifest with and are skipped.
+/// Returns true when the manifest was rewritten.
+pub fn update(note_dir: &str, repo: &Repo) -> Result<bool, Box<dyn Error>> {
+    if !key::exists(note_dir) {
+        return Ok(false);
+    }
+    let vault_key = key::note_key(note_dir)?;
+    let path = Path::new(note_dir).join(MANIFEST_FILE);
+
+    let previous = match fs::read(&path) {
+        Ok(data) => open(&data, &vault_key)?,
+        Err(_) => Manifest::default(),
+    };
+    let id_key = previous.id_key.clone().unwrap_or_else(|| {
+        let mut id_key = [0u8; 32];
+        OsRng.fill_bytes(&mut id_key);
+        encode_hex(&id_key)
+    });
+    let mut manifest = Manifest {
+        version: previous.version.max(seen_version(repo)) + 1,
+        notes: BTreeMap::new(),
+        id_key: Some(id_key),
+        access: Some(access_hashes(note_dir)?),
+        written: Some(now()),
+    };
+    for file in super::note_files(note_dir)? {
+        let data = fs::read(Path::new(note_dir).join(&file))?;
+        manifest
+            .notes
+            .insert(manifest.note_id(&file), content_hash(&data));
+    }
+
+    // Only rewrite the manifest when the notes or access files changed, or its time is due a refresh
+    let fresh = previous
+        .written
+        .is_some_and(|written| now().saturating_sub(written) < REFRESH_AFTER_SECS);
+    if manifest.notes == previous.notes
+        && manifest.access == previous.access
+        && fresh
+        && path.exists()
+    {
+        return Ok(false);
+    }
+    fs::write(&path, vault_key.encrypt(&serde_json::to_vec(&manifest)?)?)?;
+    Ok(true)
+}
+
+/// Hashes the access files in the working tree
+fn access_hashes(note_dir: &str) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut hashes = BTreeMap::new();
+    for file in super::access_files(note_dir)? {
+        let data = fs::read(Path::new(note_dir).join(&file))?;
+        hashes.insert(file, content_hash(&data));
+    }
+    Ok(hashes)
+}
+
+/// Checks that access files are as the manifest last recorded them
+///
+/// Run before wrapping keys for the recipients they list, so an access file
+/// changed behind the vault's back is never acted on. Vaults without a
+/// manifest, or with one predating access hashes, are not checked.
+pub fn check_access(note_dir: &str, files: &[&str]) -> Result<(), Box<dyn Error>> {
+    let Ok(data) = fs::read(Path::new(note_dir).join(MANIFEST_FILE)) else {
+        return Ok(());
+    };
+    if !key::exists(note_dir) {
+        return Ok(());
+    }
+    let Some(access) = open(&data, &key::note_key(note_dir)?)?.access else {
+        return Ok(());
+    };
+    for file in files {
+        let hash = fs::read(Path::new(note_dir).join(file))
+            .ok()
+            .map(|data| content_hash(&data));
+        if hash.as_ref() != access.get(*file) {
+            return Err(format!(
+                "{} differs from what the vault manifest recorded; \
+                 restore it with `git checkout` before changing access.",
+                file
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Records the local manifest's version as seen on the remote once it was pushed
+pub fn record_pushed(note_dir: &str, repo: &Repo) -> Result<(), Box<dyn Error>> {
+    let Ok(data) = fs::read(Path::new(note_dir).join(MANIFEST_FILE)) else {
+        return Ok(());
+    };
+    if !key::exists(note_dir) {
+        return Ok(());
+    }
+    let manifest = open(&data, &key::note_key(note_dir)?)?;
+    record_seen(repo, &manifest)
+}
+
+/// Returns the commit message ids of the notes among vault files
+///
+/// Vaults without a manifest have no id key, so their notes are all called `note`.
+pub fn note_ids(note_dir: &str, files: &[&str]) -> Result<Vec<String>, Box<dyn Error>> {
+    let notes = files.iter().filter(|file| file.ends_with(".secured"));
+    let path = Path::new(note_dir).join(MANIFEST_FILE);
+    let manifest = match fs::read(&path) {
+        Ok(data) if key::exists(note_dir) => Some(open(&data, &key::note_key(note_dir)?)?),
+        _ => None,
+    };
+    Ok(notes
+        .map(|file| {
+            let file = Path::new(file)
+                .strip_prefix(note_dir)
+                .unwrap_or(Path::new(file));
+            match &manifest {
+                Some(manifest) if manifest.id_key.is_some() => {
+                    manifest.note_id(&file.to_string_lossy())
+                }
+                _ => "note".to_string(),
+            }
+        })
+        .collect())
+}
+
+/// Checks a fetched commit's notes against its manifest before it is merged
+///
+/// Rejects commits whose manifest is older than one this clone has already
+/// seen on the remote or than the manifest both sides last shared, notes
+/// that are missing from, added to or swapped against it, vault keys the
+/// previous key did not hand over to, and manifests or keys changed by commits
+/// without a trusted signature. Returns the vault key the commit rotated to,
+/// which the caller switches to once it is merged.
+pub fn verify(note_dir: &str, repo: &Repo, rev: &str) -> Result<Option<VaultKey>, Box<dyn Error>> {
+    let files = repo.files_at(rev)?;
+    let mut signed = vec![MANIFEST_FILE, key::VAULT_KEY_FILE, key::ROTATION_FILE];
+    let local_access = super::access_files(note_dir).unwrap_or_default();
+    signed.extend(
+        files
+            .iter()
+            .chain(&local_access)
+            .filter(|file| super::is_access_file(file))
+            .map(String::as_str),
+    );
+    repo.verify_signed_changes(rev, &signed)?;
+    let rotated = rotated_key(note_dir, repo, rev)?;
+    let has_vault_key = files.iter().any(|file| file == key::VAULT_KEY_FILE);
+    let data = match repo.file_at(MANIFEST_FILE, rev) {
+        Ok(data) => data,
+        Err(_) if seen_version(repo) > 0 => {
+            return Err(
+                "The remote vault has no manifest although this device has seen one; \
+                        it may have been rolled back."
+                    .into(),
+            )
+        }
+        Err(_) if has_vault_key && files.iter().any(|file| file.ends_with(".secured")) => {
+            eprintln!("Warning: the remote vault has no manifest yet; it will be created on the next save.");
+            return Ok(rotated);
+        }
+        Err(_) => return Ok(rotated),
+    };
+
+    let vault_key = match &rotated {
+        Some(vault_key) => NoteKey::Vault(VaultKey::from_bytes(vault_key.as_bytes())?),
+        // A first clone without a member key trusts the manifest it is first given
+        None if !key::exists(note_dir) && seen_version(repo) == 0 => return Ok(None),
+        None => key::note_key(note_dir)?,
+    };
+    let manifest = open(&data, &vault_key)?;
+    let seen = seen_version(repo);
+    if manifest.version < seen {
+        return Err(format!(
+            "The remote vault is at manifest version {} but this device has already seen version {}; \
+             refusing a possible rollback.",
+            manifest.version, seen
+        )
+        .into());
+    }
+    // After diverging, the remote must still be newer than where both sides last agreed
+    if let Some(base) = repo.merge_base(rev)? {
+        let base_manifest = repo
+            .file_at(MANIFEST_FILE, &base)
+            .ok()
+            .and_then(|data| open(&data, &vault_key).ok());
+        if let Some(base_manifest) = base_manifest {
+            if manifest.version < base_manifest.version {
+                return Err(format!(
+                    "The remote vault is at manifest version {} but was already at version {} \
+                     when this device last shared its history; refusing a possible rollback.",
+                    manifest.version, base_manifest.version
+                )
+                .into());
+            }
+        }
+    }
+
+    let mut problems = Vec::new();
+    let mut listed = manifest.notes.clone();
+    let notes = files.iter().filter(|file| file.ends_with(".secured"));
+    for file in notes {
+        let id = manifest.note_id(file);
+        let hash = content_hash(&repo.file_at(file, rev)?);
+        match listed.remove(&id) {
+            Some(expected) if expected == hash => {}
+            Some(_) => problems.push(format!("{} does not match the manifest", file)),
+            None => problems.push(format!("{} is not listed in the manifest", file)),
+        }
+    }
+    for id in listed.keys() {
+        problems.push(format!("note {} listed in the manifest is missing", id));
+    }
+    if let Some(mut listed) = manifest.access.clone() {
+        for file in files.iter().filter(|file| super::is_access_file(file)) {
+            let hash = content_hash(&repo.file_at(file, rev)?);
+            match listed.remove(file) {
+                Some(expected) if expected == hash => {}
+                Some(_) => problems.push(format!("{} does not match the manifest", file)),
+                None => problems.push(format!("{} is not listed in the manifest", file)),
+            }
+        }
+        for file in listed.keys() {
+            problems.push(format!("{} listed in the manifest is missing", file));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(format!(
+            "Refusing to pull files that fail the manifest check:\n  {}",
+            problems.join("\n  ")
+        )
+        .into());
+    }
+
+    record_seen(repo, &manifest)?;
+    Ok(rotated)
+}
+
+/// Returns a fetched commit's vault key when it was rotated on the remote
+///
+/// Every fetched commit that changes the vault key must either keep it, carry
+/// a rotation record the key before it vouches for, or be signed by a trusted
+/// device, so pushing a key of one's own wrapped to every member is refused.
+/// Signatures cover rotations made before rotation records existed. A device without a vault key
+/// yet trusts the one it is first given. The new key is only returned; the
+/// caller switches to it once the pull went through.
+fn rotated_key(note_dir: &str, repo: &Repo, rev: &str) -> Result<Option<VaultKey>, Box<dyn Error>> {
+    let key_files = [
+        members::WRAPPED_KEY_FILE,
+        key::VAULT_KEY_FILE,
+        key::ROTATION_FILE,
+    ];
+    // A first clone has no key to compare against, so asking for the passphrase gains nothing
+    let first_clone = !key::exists(note_dir) && !Path::new(note_dir).join(MANIFEST_FILE).exists();
+    let mut current: Option<NoteKey> = None;
+    let mut rotated = None;
+    for oid in repo.incoming_commits(Oid::from_str(rev)?)? {
+        if !repo.changes_any(oid, &key_files)? {
+            continue;
+        }
+        let commit = oid.to_string();
+        let Some(vault_key) = key_at(repo, oid, !first_clone)? else {
+            continue;
+        };
+        if current.is_none() && key::exists(note_dir) {
+            current = Some(key::note_key(note_dir)?);
+        }
+        if let Some(previous) = &current {
+            if key::is_same(previous, &vault_key) {
+                continue;
+            }
+            let bound = repo
+                .file_at(key::ROTATION_FILE, &commit)
+                .is_ok_and(|record| key::is_bound(&record, previous, &vault_key));
+            if !bound && !repo.is_trusted_commit(oid) {
+                return Err(format!(
+                    "Commit {} replaces the vault key without the previous key vouching for it \
+                     or a trusted signature; refusing to pull it.",
+                    &commit[..7]
+                )
+                .into());
+            }
+        }
+        current = Some(NoteKey::Vault(VaultKey::from_bytes(vault_key.as_bytes())?));
+        rotated = Some(vault_key);
+    }
+    Ok(rotated)
+}
+
+/// Opens the vault key as of a fetched commit, with the member key or else the master passphrase
+///
+/// Returns None when this device cannot open that commit's key or, without a
+/// member key, when the commit left the passphrase-protected key alone or
+/// `ask_passphrase` is false.
+fn key_at(repo: &Repo, oid: Oid, ask_passphrase: bool) -> Result<Option<VaultKey>, Box<dyn Error>> {
+    let rev = oid.to_string();
+    let passphrase_key = repo.file_at(key::VAULT_KEY_FILE, &rev).ok();
+    if let Ok(wrapped) = repo.file_at(members::WRAPPED_KEY_FILE, &rev) {
+        match members::unwrap(&wrapped) {
+            Ok(Some(vault_key)) => return Ok(Some(vault_key)),
+            Err(e) if passphrase_key.is_none() => return Err(e),
+            _ => {}
+        }
+    }
+    let Some(data) = passphrase_key else {
+        return Ok(None);
+    };
+    if !ask_passphrase || !repo.changes_any(oid, &[key::VAULT_KEY_FILE])? {
+        return Ok(None);
+    }
+    let passphrase =
+        prompt::password("The vault key changed on the remote. Enter its master passphrase:")?;
+    let bytes = crypto::decrypt(&data, &passphrase).map_err(|_| "Incorrect master passphrase.")?;
+    Ok(Some(VaultKey::from_bytes(&bytes)?))
+}
+
+/// Re-encrypts the manifest with a new vault key
+pub fn rekey(note_dir: &str, old: &NoteKey, new: &NoteKey) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(note_dir).join(MANIFEST_FILE);
+    let Ok(data) = fs::read(&path) else {
+        return Ok(());
+    };
+    let plaintext = old.decrypt(&data)?;
+    workspace::write_atomic(&path, &new.encrypt(&plaintext)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KdfParams;
+    use git2::{Repository, ResetType};
+    use std::env;
+
+    /// Cheap costs so wrapping the test vault key does not spend seconds in Argon2
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    /// Writes a note, stages it with the manifest and commits, returning the commit
+    fn save(note_dir: &str, repo: &Repo, note: &[u8], update_manifest: bool) -> Oid {
+        fs::write(Path::new(note_dir).join("general.secured"), note).unwrap();
+        if update_manifest {
+            update(note_dir, repo).unwrap();
+        }
+        repo.stage(&[MANIFEST_FILE, key::VAULT_KEY_FILE, "general.secured"])
+            .unwrap();
+        repo.commit("save").unwrap().unwrap()
+    }
+
+    /// Moves the vault's branch and working tree back to an earlier commit
+    fn reset(note_dir: &str, oid: Oid) {
+        let git = Repository::open(note_dir).unwrap();
+        let commit = git.find_object(oid, None).unwrap();
+        git.reset(&commit, ResetType::Hard, None).unwrap();
+    }
+
+    /// Returns why verify refused a commit
+    fn refusal(note_dir: &str, repo: &Repo, oid: Oid) -> String {
+        match verify(note_dir, repo, &oid.to_string()) {
+            Ok(_) => panic!("commit {} was accepted", oid),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn verify_checks_notes_and_refuses_rollbacks() {
+        let dir = env::temp_dir().join(format!("prive-manifest-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let note_dir = dir.to_str().unwrap();
+        let repo = Repo::init(note_dir, "file:///nonexistent", Some("main")).unwrap();
+
+        let vault_key = VaultKey::generate();
+        let wrapped = crypto::encrypt_with_params(vault_key.as_bytes(), "pw", &TEST_KDF).unwrap();
+        fs::write(key::key_path(note_dir), wrapped).unwrap();
+        key::replace(note_dir, &vault_key).unwrap();
+
+        let first = save(note_dir, &repo, b"first", true);
+        assert!(!update(note_dir, &repo).unwrap(), "nothing changed");
+        let second = save(note_dir, &repo, b"second", true);
+        let tampered = save(note_dir, &repo, b"tampered", false);
+        reset(note_dir, first);
+        fs::remove_file(Path::new(note_dir).join(MANIFEST_FILE)).unwrap();
+        repo.stage(&[MANIFEST_FILE]).unwrap();
+        let unlisted = repo.commit("drop manifest").unwrap().unwrap();
+        reset(note_dir, first);
+
+        // A newer manifest that lists the notes as they are is accepted and remembered
+        assert!(verify(note_dir, &repo, &second.to_string())
+            .unwrap()
+            .is_none());
+        assert_eq!(seen_version(&repo), 2);
+
+        assert!(refusal(note_dir, &repo, tampered).contains("does not match the manifest"));
+        // Once version 2 was seen, older or missing manifests are rollbacks
+        assert!(refusal(note_dir, &repo, first).contains("rollback"));
+        assert!(refusal(note_dir, &repo, unlisted).contains("rolled back"));
+
+        key::forget(note_dir);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
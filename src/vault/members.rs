@@ -0,0 +1,230 @@
+use crate::config;
+use crate::crypto::{self, VaultKey};
+use crate::{prompt, workspace};
+use age::secrecy::ExposeSecret;
+use age::x25519::{Identity, Recipient};
+use serde::Serialize;
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::iter;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// File in the vault listing the members of a shared vault and their age recipients
+pub const MEMBERS_FILE: &str = "vault.members";
+
+/// File in the vault holding the vault key encrypted with age to every member
+pub const WRAPPED_KEY_FILE: &str = "vault.key.age";
+
+/// Environment variable naming the member key file to use instead of the default one
+pub const IDENTITY_ENV: &str = "PRIVE_IDENTITY";
+
+/// Member key loaded earlier in this process, so its passphrase is asked at most once
+static IDENTITY: Mutex<Option<Identity>> = Mutex::new(None);
+
+/// A person or device that can open the vault key with their own age identity
+#[derive(Serialize, Clone)]
+pub struct Member {
+    /// Name the member was added under
+    pub name: String,
+    /// age X25519 recipient, `age1...`
+    pub recipient: String,
+}
+
+impl Member {
+    /// Formats the member as a line of the members file
+    pub fn to_line(&self) -> String {
+        format!("{} {}", self.name, self.recipient)
+    }
+}
+
+/// Parses the members file, skipping comments and malformed lines
+pub fn parse(text: &str) -> Vec<Member> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (name, recipient) = line.split_once(' ')?;
+            Some(Member {
+                name: name.to_string(),
+                recipient: recipient.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Returns the vault's members, or none if the vault is not shared
+pub fn load(note_dir: &str) -> Result<Vec<Member>, Box<dyn Error>> {
+    match fs::read_to_string(Path::new(note_dir).join(MEMBERS_FILE)) {
+        Ok(text) => Ok(parse(&text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", MEMBERS_FILE, e).into()),
+    }
+}
+
+/// Writes the members file
+pub fn save(note_dir: &str, members: &[Member]) -> Result<(), Box<dyn Error>> {
+    let mut text = String::from("# Managed by prive: members who can open the vault key.\n");
+    for member in members {
+        text.push_str(&member.to_line());
+        text.push('\n');
+    }
+    workspace::write_atomic(&Path::new(note_dir).join(MEMBERS_FILE), text.as_bytes())?;
+    Ok(())
+}
+
+/// Returns true if the vault key is wrapped for members
+pub fn is_shared(note_dir: &str) -> bool {
+    Path::new(note_dir).join(WRAPPED_KEY_FILE).exists()
+}
+
+/// Parses an age X25519 recipient
+pub fn parse_recipient(recipient: &str) -> Result<Recipient, String> {
+    recipient
+        .parse()
+        .map_err(|e| format!("'{}' is not an age X25519 recipient: {}", recipient, e))
+}
+
+/// Encrypts the vault key with age to every member and writes it to the vault
+pub fn wrap(
+    note_dir: &str,
+    vault_key: &VaultKey,
+    members: &[Member],
+) -> Result<(), Box<dyn Error>> {
+    let recipients = members
+        .iter()
+        .map(|member| parse_recipient(&member.recipient))
+        .collect::<Result<Vec<_>, _>>()?;
+    let encryptor = age::Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )?;
+
+    let mut data = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut data)?;
+    writer.write_all(vault_key.as_bytes())?;
+    writer.finish()?;
+    workspace::write_atomic(&Path::new(note_dir).join(WRAPPED_KEY_FILE), &data)?;
+    Ok(())
+}
+
+/// Returns how many recipients an age file is encrypted to, from the stanzas in its header
+///
+/// Grease stanzas, which age adds at random and nobody can open, are not counted.
+pub fn recipient_count(data: &[u8]) -> Result<usize, Box<dyn Error>> {
+    let mut count = 0;
+    for line in data.split(|byte| *byte == b'\n') {
+        if line.starts_with(b"---") {
+            return Ok(count);
+        }
+        let Some(stanza) = line.strip_prefix(b"-> ") else {
+            continue;
+        };
+        let tag = stanza
+            .split(|byte| *byte == b' ')
+            .next()
+            .unwrap_or_default();
+        if !tag.ends_with(b"-grease") {
+            count += 1;
+        }
+    }
+    Err("The wrapped key has no valid age header.".into())
+}
+
+/// Opens a wrapped vault key with this device's member key
+///
+/// Returns None when this device has no member key.
+pub fn unwrap(data: &[u8]) -> Result<Option<VaultKey>, Box<dyn Error>> {
+    let Some(identity) = load_identity()? else {
+        return Ok(None);
+    };
+    let decryptor = age::Decryptor::new_buffered(data)?;
+    let mut reader = decryptor
+        .decrypt(iter::once(&identity as &dyn age::Identity))
+        .map_err(|_| {
+            format!(
+                "Your member key {} is not a member of this vault; ask a member to run `prive member add`.",
+                identity.to_public()
+            )
+        })?;
+    let mut bytes = Zeroizing::new(Vec::new());
+    reader.read_to_end(&mut bytes)?;
+    Ok(Some(VaultKey::from_bytes(&bytes)?))
+}
+
+/// Returns the path of this device's member key
+pub fn identity_path() -> Option<PathBuf> {
+    match env::var(IDENTITY_ENV) {
+        Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => config::config_dir().map(|dir| dir.join("identity")),
+    }
+}
+
+/// Loads this device's member key, prompting for its passphrase if it is encrypted
+///
+/// Plain identity files written by `age-keygen` are accepted as well.
+pub fn load_identity() -> Result<Option<Identity>, Box<dyn Error>> {
+    if let Some(identity) = IDENTITY.lock().ok().and_then(|identity| identity.clone()) {
+        return Ok(Some(identity));
+    }
+    let Some(path) = identity_path() else {
+        return Ok(None);
+    };
+    let data = match fs::read(&path) {
+        Ok(data) => Zeroizing::new(data),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+    };
+    let text = if crypto::is_prive_format(&data) {
+        let passphrase = prompt::password("Enter the passphrase for your member key:")?;
+        crypto::decrypt(&data, &passphrase).map_err(|_| "Incorrect member key passphrase.")?
+    } else {
+        data
+    };
+
+    let text = std::str::from_utf8(&text).map_err(|_| "The member key file is not text.")?;
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| format!("{} holds no age identity.", path.display()))?;
+    let identity: Identity = line
+        .parse()
+        .map_err(|e| format!("{} holds no valid age identity: {}", path.display(), e))?;
+    if let Ok(mut cached) = IDENTITY.lock() {
+        *cached = Some(identity.clone());
+    }
+    Ok(Some(identity))
+}
+
+/// Generates this device's member key and stores it encrypted with a new passphrase
+pub fn create_identity() -> Result<Identity, Box<dyn Error>> {
+    let path =
+        identity_path().ok_or("HOME is not set; give the member key path in PRIVE_IDENTITY.")?;
+    println!("Creating your member key. Its passphrase unlocks every vault you are a member of.");
+    let passphrase = prompt::new_password("Choose a passphrase for your member key:")?;
+
+    let identity = Identity::generate();
+    if let Ok(mut cached) = IDENTITY.lock() {
+        *cached = Some(identity.clone());
+    }
+    let secret = identity.to_string();
+    let data = crypto::encrypt(secret.expose_secret().as_bytes(), &passphrase)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&data))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    println!("Your member key was saved to {}.", path.display());
+    Ok(identity)
+}
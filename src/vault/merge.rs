@@ -0,0 +1,150 @@
+use super::key::{self, NoteKey};
+use super::manifest::MANIFEST_FILE;
+use super::members;
+use super::policy::{self, POLICY_FILE};
+use super::repo::Conflict;
+use crate::config::Config;
+use crate::crypto::{self, KeyMode};
+use crate::{editor, prompt, workspace};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Marker diffy writes at the start of every unresolved conflict
+const CONFLICT_MARKER: &[u8] = b"<<<<<<<";
+
+/// Merges a vault file that was changed both on this device and on the remote
+///
+/// Encrypted notes are decrypted, merged as text and re-encrypted with the key
+/// that opened this device's version; other vault metadata is merged as plain text.
+pub fn resolve(config: &Config, conflict: &Conflict) -> Result<Vec<u8>, Box<dyn Error>> {
+    let note_dir = config.note_dir.as_str();
+    let key_files = [
+        key::VAULT_KEY_FILE,
+        key::ROTATION_FILE,
+        members::WRAPPED_KEY_FILE,
+        members::MEMBERS_FILE,
+        POLICY_FILE,
+    ];
+    // Folders keep their own wrapped key and members file under the same names
+    let file_name = Path::new(&conflict.path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    if key_files.contains(&file_name) {
+        return Err(format!(
+            "{} was changed on both devices and cannot be merged.",
+            conflict.path
+        )
+        .into());
+    }
+    // The manifest is rebuilt from the merged notes once the merge is committed
+    if conflict.path == MANIFEST_FILE {
+        return Ok(conflict.ours.clone());
+    }
+    if !conflict.path.ends_with(".secured") {
+        let merged = merge_text(
+            config,
+            &conflict.path,
+            conflict.ancestor.as_deref().unwrap_or_default(),
+            &conflict.ours,
+            &conflict.theirs,
+        )?;
+        return Ok(merged.to_vec());
+    }
+
+    println!("'{}' was changed on both devices; merging.", conflict.path);
+    let mut keys = Vec::new();
+    let ours = decrypt_side(note_dir, &conflict.path, &conflict.ours, &mut keys)?;
+    let theirs = decrypt_side(note_dir, &conflict.path, &conflict.theirs, &mut keys)?;
+    let ancestor = match &conflict.ancestor {
+        Some(data) => decrypt_side(note_dir, &conflict.path, data, &mut keys)?,
+        None => Zeroizing::new(Vec::new()),
+    };
+
+    let merged = merge_text(config, &conflict.path, &ancestor, &ours, &theirs)?;
+    keys[0].encrypt(&merged)
+}
+
+/// Decrypts one side of a conflicting note, reusing keys that already opened another side
+fn decrypt_side(
+    note_dir: &str,
+    path: &str,
+    data: &[u8],
+    keys: &mut Vec<NoteKey>,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    if !crypto::is_prive_format(data) {
+        return Err(format!(
+            "'{}' has a legacy version; open and save it on both devices before syncing.",
+            path
+        )
+        .into());
+    }
+
+    let mode = crypto::key_mode(data)?;
+    for key in keys.iter().filter(|key| key.mode() == mode) {
+        if let Ok(plaintext) = key.decrypt(data) {
+            return Ok(plaintext);
+        }
+    }
+
+    let key = match mode {
+        KeyMode::Vault => policy::note_key(note_dir, path)?,
+        KeyMode::Password => NoteKey::Password(prompt::password(&format!(
+            "Enter the password for {}:",
+            path
+        ))?),
+    };
+    let plaintext = key.decrypt(data)?;
+    keys.push(key);
+    Ok(plaintext)
+}
+
+/// Three-way merges plaintext, opening the editor when the sides overlap
+fn merge_text(
+    config: &Config,
+    name: &str,
+    ancestor: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    match diffy::merge_bytes(ancestor, ours, theirs) {
+        Ok(merged) => Ok(Zeroizing::new(merged)),
+        Err(conflicted) => edit_conflicts(config, name, Zeroizing::new(conflicted)),
+    }
+}
+
+/// Lets the user resolve conflict markers in a private working copy
+fn edit_conflicts(
+    config: &Config,
+    name: &str,
+    conflicted: Zeroizing<Vec<u8>>,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let note_name = name.strip_suffix(".secured").unwrap_or(name);
+    let working_copy = workspace::WorkingCopy::create(note_name, &conflicted)?;
+
+    loop {
+        println!(
+            "Both devices changed the same lines of '{}'. Resolve the conflict markers in the editor.",
+            note_name
+        );
+        editor::edit(config, working_copy.path())
+            .map_err(|e| format!("{} The merge was aborted.", e))?;
+
+        let merged = working_copy.read()?;
+        if !merged
+            .windows(CONFLICT_MARKER.len())
+            .any(|window| window == CONFLICT_MARKER)
+        {
+            return Ok(merged);
+        }
+
+        println!("Conflict markers remain. Edit again? (yes/no)");
+        let mut choice = String::new();
+        io::stdin().read_line(&mut choice)?;
+        if !choice.trim().eq_ignore_ascii_case("yes") {
+            return Err(format!("The merge of '{}' was aborted.", note_name).into());
+        }
+    }
+}
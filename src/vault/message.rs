@@ -0,0 +1,141 @@
+use super::repo::format_time;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::ffi::CStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Git config key in the vault repository holding the commit message template
+pub const TEMPLATE_CONFIG: &str = "prive.commitTemplate";
+
+/// Git config key in the vault repository naming this device in commit messages
+pub const DEVICE_CONFIG: &str = "prive.device";
+
+/// Template used when none is configured or the configured one is invalid
+pub const DEFAULT_TEMPLATE: &str = "{action} {note} from {device}";
+
+/// Placeholders a template may use; nothing else is ever substituted
+const PLACEHOLDERS: [&str; 4] = ["action", "timestamp", "device", "note"];
+
+/// Returns a stable id for a note that stands in for its name in commit metadata
+///
+/// The id is an HMAC of the note's path relative to the vault root under the
+/// vault's id key, so every member agrees on it while nobody without the key
+/// can recover a name by hashing guesses.
+pub fn note_id(id_key: &[u8], relative_path: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(id_key).expect("HMAC accepts keys of any length");
+    mac.update(relative_path.as_bytes());
+    mac.finalize().into_bytes()[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks that a template only uses the supported placeholders
+pub fn validate(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in commit template '{}'.", template))?;
+        let name = &rest[start + 1..start + end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "Unknown placeholder '{{{}}}' in commit template; use {}.",
+                name,
+                PLACEHOLDERS.map(|name| format!("{{{}}}", name)).join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// Renders a commit message for an action on the notes with the given ids
+///
+/// Only the action word, the time, the device name and opaque note ids are
+/// substituted, so note titles and contents cannot reach commit metadata.
+pub fn render(template: &str, action: &str, note_ids: &[String], device: &str) -> String {
+    let note = if note_ids.is_empty() {
+        "vault".to_string()
+    } else {
+        note_ids.join(" ")
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| format_time(elapsed.as_secs() as i64))
+        .unwrap_or_default();
+
+    let mut message = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        message.push_str(&rest[..start]);
+        match &rest[start + 1..start + end] {
+            "action" => message.push_str(action),
+            "timestamp" => message.push_str(&timestamp),
+            "device" => message.push_str(device),
+            "note" => message.push_str(&note),
+            _ => {}
+        }
+        rest = &rest[start + end + 1..];
+    }
+    message.push_str(rest);
+    message
+}
+
+/// Returns the host name used as the default device name
+pub fn device_name() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "unknown device".to_string();
+    }
+    CStr::from_bytes_until_nul(&buffer)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown device".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_only_use_known_placeholders() {
+        assert!(validate(DEFAULT_TEMPLATE).is_ok());
+        assert!(validate("{timestamp}: {action} {note}").is_ok());
+        assert!(validate("no placeholders").is_ok());
+        assert!(validate("{action} {title}").is_err());
+        assert!(validate("{action} {note").is_err());
+        assert!(validate("{}").is_err());
+    }
+
+    #[test]
+    fn renders_only_action_device_and_note_ids() {
+        let ids = ["0123456789ab".to_string(), "ba9876543210".to_string()];
+        assert_eq!(
+            render(DEFAULT_TEMPLATE, "edit", &ids, "laptop"),
+            "edit 0123456789ab ba9876543210 from laptop"
+        );
+        assert_eq!(
+            render("[{device}] {action} {note}.", "sync", &[], "phone"),
+            "[phone] sync vault."
+        );
+
+        let message = render("{timestamp} {action}", "edit", &[], "laptop");
+        assert!(message.ends_with(" edit"));
+        assert!(!message.contains('{'));
+    }
+
+    #[test]
+    fn note_ids_are_keyed() {
+        let id = note_id(b"key one", "prod/db.secured");
+        assert_eq!(id.len(), 12);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(id, note_id(b"key one", "prod/db.secured"));
+        assert_ne!(id, note_id(b"key two", "prod/db.secured"));
+        assert_ne!(id, note_id(b"key one", "prod/web.secured"));
+    }
+}
@@ -0,0 +1,131 @@
+pub mod key;
+pub mod manifest;
+pub mod members;
+pub mod merge;
+pub mod message;
+pub mod policy;
+pub mod repo;
+pub mod signing;
+
+pub use repo::Repo;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the ignore file prive maintains at the root of the vault
+pub const GITIGNORE_FILE: &str = ".gitignore";
+
+/// Ignore rules that keep everything except encrypted files out of git
+const GITIGNORE: &str = "\
+# Managed by prive: only encrypted files and vault metadata may be committed.
+*
+!*/
+!*.secured
+!.gitignore
+!.gitattributes
+!vault.key
+!vault.key.age
+!vault.key.rotation
+!vault.members
+!vault.policy
+!vault.manifest
+!note-db.json
+!trusted-signers
+";
+
+/// Name of the attributes file that routes notes through the textconv driver
+pub const GITATTRIBUTES_FILE: &str = ".gitattributes";
+
+/// Name of the git diff driver that decrypts notes
+pub const DIFF_DRIVER: &str = "prive";
+
+/// Attributes that make git diff notes through `prive git-textconv`
+const GITATTRIBUTES: &str = "\
+# Managed by prive: show decrypted diffs of notes while the vault is unlocked.
+*.secured diff=prive
+";
+
+/// Writes the vault's `.gitignore`, returning true if it was created or changed
+pub fn ensure_gitignore(note_dir: &str) -> io::Result<bool> {
+    let path = Path::new(note_dir).join(GITIGNORE_FILE);
+    if fs::read_to_string(&path).ok().as_deref() == Some(GITIGNORE) {
+        return Ok(false);
+    }
+    fs::write(path, GITIGNORE)?;
+    Ok(true)
+}
+
+/// Returns the vault metadata files that exist and are committed alongside notes
+pub fn metadata_files(note_dir: &str) -> Vec<&'static str> {
+    [
+        GITIGNORE_FILE,
+        GITATTRIBUTES_FILE,
+        key::VAULT_KEY_FILE,
+        members::WRAPPED_KEY_FILE,
+        key::ROTATION_FILE,
+        members::MEMBERS_FILE,
+        policy::POLICY_FILE,
+        manifest::MANIFEST_FILE,
+        signing::TRUSTED_SIGNERS_FILE,
+    ]
+    .into_iter()
+    .filter(|file| Path::new(note_dir).join(file).exists())
+    .collect()
+}
+
+/// Returns the paths of the vault's notes relative to its root, including notes in folders
+pub fn note_files(note_dir: &str) -> io::Result<Vec<String>> {
+    list_files(note_dir, |name| name.ends_with(".secured"))
+}
+
+/// Returns true if a vault file decides who can open the vault or a folder's notes
+///
+/// These are the member lists and wrapped keys of the vault and of every
+/// folder, the policy naming each folder's readers, and the devices trusted
+/// to sign commits.
+pub fn is_access_file(path: &str) -> bool {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    path == policy::POLICY_FILE
+        || path == signing::TRUSTED_SIGNERS_FILE
+        || [members::MEMBERS_FILE, members::WRAPPED_KEY_FILE].contains(&name)
+}
+
+/// Returns the paths of the vault's access files relative to its root
+pub fn access_files(note_dir: &str) -> io::Result<Vec<String>> {
+    let mut files = list_files(note_dir, |_| true)?;
+    files.retain(|file| is_access_file(file));
+    Ok(files)
+}
+
+/// Lists the files in the vault and its folders whose names pass a filter, sorted
+fn list_files(note_dir: &str, keep: impl Fn(&str) -> bool) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(Path::new(note_dir).join(&dir))?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            if path.is_dir() && !name.starts_with('.') {
+                dirs.push(format!("{}{}/", dir, name));
+            } else if keep(&name) && path.is_file() {
+                files.push(format!("{}{}", dir, name));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Writes the vault's `.gitattributes`, returning true if it was created or changed
+pub fn ensure_gitattributes(note_dir: &str) -> io::Result<bool> {
+    let path = Path::new(note_dir).join(GITATTRIBUTES_FILE);
+    if fs::read_to_string(&path).ok().as_deref() == Some(GITATTRIBUTES) {
+        return Ok(false);
+    }
+    fs::write(path, GITATTRIBUTES)?;
+    Ok(true)
+}
@@ -0,0 +1,489 @@
+use super::key::{self, NoteKey};
+use super::manifest;
+use super::members::{self, Member};
+use super::repo::Repo;
+use crate::crypto::{self, KeyMode, VaultKey};
+use crate::{prompt, workspace};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// File in the vault limiting which members can read the notes in a folder
+pub const POLICY_FILE: &str = "vault.policy";
+
+/// Folder keys unlocked earlier in this process, by folder
+static UNLOCKED: Mutex<BTreeMap<String, VaultKey>> = Mutex::new(BTreeMap::new());
+
+/// Members allowed to read the notes in a folder and its subfolders
+#[derive(Serialize)]
+pub struct Rule {
+    /// Folder relative to the vault root, ending in `/`
+    pub folder: String,
+    /// Names of the members, as listed in the members file
+    pub members: Vec<String>,
+}
+
+/// Per-folder readers of a shared vault; notes outside every folder are readable by all members
+#[derive(Default)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Returns the rule for the most specific folder containing a note
+    pub fn rule_for(&self, file: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| file.starts_with(&rule.folder))
+            .max_by_key(|rule| rule.folder.len())
+    }
+
+    /// Returns the members of a rule, who are the only ones its folder key is wrapped for
+    pub fn recipients(&self, rule: &Rule, members: &[Member]) -> Vec<Member> {
+        members
+            .iter()
+            .filter(|member| rule.members.contains(&member.name))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Parses the policy file, checking that every folder is valid and every name is a member
+///
+/// Each line names a folder followed by the members allowed to read it:
+/// `prod/ alice carol`.
+pub fn parse(text: &str, members: &[Member]) -> Result<Policy, String> {
+    let mut rules: Vec<Rule> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let folder = words.next().unwrap_or_default();
+        let valid = folder.len() > 1
+            && folder.ends_with('/')
+            && folder
+                .trim_end_matches('/')
+                .split('/')
+                .all(|part| !part.is_empty() && !part.starts_with('.'));
+        if !valid {
+            return Err(format!(
+                "{}: '{}' is not a folder like `prod/`.",
+                POLICY_FILE, folder
+            ));
+        }
+        if rules.iter().any(|rule| rule.folder == folder) {
+            return Err(format!("{}: '{}' is listed twice.", POLICY_FILE, folder));
+        }
+
+        let names: Vec<String> = words.map(str::to_string).collect();
+        if names.is_empty() {
+            return Err(format!("{}: '{}' names no members.", POLICY_FILE, folder));
+        }
+        if let Some(unknown) = names
+            .iter()
+            .find(|name| !members.iter().any(|member| &member.name == *name))
+        {
+            return Err(format!(
+                "{}: '{}' is not a member of the vault.",
+                POLICY_FILE, unknown
+            ));
+        }
+        rules.push(Rule {
+            folder: folder.to_string(),
+            members: names,
+        });
+    }
+    Ok(Policy { rules })
+}
+
+/// Loads the vault's policy; vaults without a policy file have no rules
+pub fn load(note_dir: &str, members: &[Member]) -> Result<Policy, Box<dyn Error>> {
+    match fs::read_to_string(Path::new(note_dir).join(POLICY_FILE)) {
+        Ok(text) => Ok(parse(&text, members)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Policy::default()),
+        Err(e) => Err(format!("Failed to read {}: {}", POLICY_FILE, e).into()),
+    }
+}
+
+/// Returns a vault file's path relative to the vault root
+fn relative(note_dir: &str, file: &str) -> String {
+    let path = Path::new(file);
+    path.strip_prefix(note_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns a copy of a vault key
+fn copy(vault_key: &VaultKey) -> Result<VaultKey, Box<dyn Error>> {
+    VaultKey::from_bytes(vault_key.as_bytes())
+}
+
+/// Unlocks the key of a folder with this device's member key
+fn folder_key(note_dir: &str, folder: &str) -> Result<VaultKey, Box<dyn Error>> {
+    if let Some(vault_key) = UNLOCKED.lock().ok().and_then(|unlocked| {
+        let vault_key = unlocked.get(folder)?;
+        copy(vault_key).ok()
+    }) {
+        return Ok(vault_key);
+    }
+
+    let path = Path::new(note_dir)
+        .join(folder)
+        .join(members::WRAPPED_KEY_FILE);
+    let data = fs::read(path)
+        .map_err(|_| format!("'{}' has no key yet; run `prive policy apply`.", folder))?;
+    // Only a key that does not open the folder means the member is not allowed in
+    if members::load_identity()?.is_none() {
+        return Err(format!(
+            "The notes in '{}' are opened with a member key, and this device has none.",
+            folder
+        )
+        .into());
+    }
+    let vault_key = members::unwrap(&data)
+        .map_err(|_| format!("You are not allowed to read the notes in '{}'.", folder))?
+        .ok_or("This device has no member key.")?;
+    if let Ok(mut unlocked) = UNLOCKED.lock() {
+        unlocked.insert(folder.to_string(), copy(&vault_key)?);
+    }
+    Ok(vault_key)
+}
+
+/// Returns the folder whose key protects a note, the nearest one holding a wrapped key
+///
+/// This is where the policy was last applied, which may lag behind an edited policy file.
+pub fn scope(note_dir: &str, file: &str) -> Option<String> {
+    let file = relative(note_dir, file);
+    Path::new(&file)
+        .ancestors()
+        .skip(1)
+        .map(|folder| folder.to_string_lossy().into_owned())
+        .take_while(|folder| !folder.is_empty())
+        .find(|folder| {
+            Path::new(note_dir)
+                .join(folder)
+                .join(members::WRAPPED_KEY_FILE)
+                .exists()
+        })
+        .map(|folder| format!("{}/", folder))
+}
+
+/// Returns the key for a vault note: its folder's key, or the vault key outside every folder
+pub fn note_key(note_dir: &str, file: &str) -> Result<NoteKey, Box<dyn Error>> {
+    match scope(note_dir, file) {
+        Some(folder) => Ok(NoteKey::Vault(folder_key(note_dir, &folder)?)),
+        None => key::note_key(note_dir),
+    }
+}
+
+/// Opens the key that protected a vault note at an earlier revision
+///
+/// Keys retired since then are unwrapped from that revision's key files with
+/// this device's member key, or with the master passphrase of the time.
+/// They are only ever used to read old revisions, never to encrypt.
+pub fn note_key_at(
+    note_dir: &str,
+    repo: &Repo,
+    file: &str,
+    rev: &str,
+) -> Result<VaultKey, Box<dyn Error>> {
+    let file = relative(note_dir, file);
+    let folders = Path::new(&file)
+        .ancestors()
+        .skip(1)
+        .map(|folder| folder.to_string_lossy().into_owned())
+        .take_while(|folder| !folder.is_empty());
+    for folder in folders {
+        let wrapped = format!("{}/{}", folder, members::WRAPPED_KEY_FILE);
+        if let Ok(data) = repo.file_at(&wrapped, rev) {
+            return members::unwrap(&data)
+                .map_err(|_| format!("You were not allowed to read '{}/' at {}.", folder, rev))?
+                .ok_or_else(|| "This device has no member key.".into());
+        }
+    }
+
+    if let Ok(data) = repo.file_at(members::WRAPPED_KEY_FILE, rev) {
+        if let Ok(Some(vault_key)) = members::unwrap(&data) {
+            return Ok(vault_key);
+        }
+    }
+    let data = repo
+        .file_at(key::VAULT_KEY_FILE, rev)
+        .map_err(|_| format!("The vault key of {} cannot be opened on this device.", rev))?;
+    let passphrase =
+        prompt::password(&format!("Enter the vault master passphrase as of {}:", rev))?;
+    let bytes = crypto::decrypt(&data, &passphrase).map_err(|_| "Incorrect master passphrase.")?;
+    VaultKey::from_bytes(&bytes)
+}
+
+/// Refuses a note whose key could be opened by anyone the policy does not allow
+///
+/// The key of the note's folder must be wrapped for exactly the members the
+/// policy names, and the note must be encrypted with that key.
+pub fn check(note_dir: &str, file: &str) -> Result<(), Box<dyn Error>> {
+    let file = relative(note_dir, file);
+    let Ok(data) = fs::read(Path::new(note_dir).join(&file)) else {
+        return Ok(());
+    };
+    // Notes with their own password are readable by whoever knows it
+    if !crypto::is_prive_format(&data) || crypto::key_mode(&data)? != KeyMode::Vault {
+        return Ok(());
+    }
+    let members = members::load(note_dir)?;
+    if members.is_empty() {
+        return Ok(());
+    }
+
+    let policy = load(note_dir, &members)?;
+    let (folder, expected) = match policy.rule_for(&file) {
+        Some(rule) => (rule.folder.as_str(), policy.recipients(rule, &members)),
+        None => ("", members.clone()),
+    };
+    let scope_name = if folder.is_empty() {
+        "the vault root"
+    } else {
+        folder
+    };
+    if scope(note_dir, &file).unwrap_or_default() != folder {
+        return Err(format!(
+            "Refusing to save '{}': {} changed where it belongs; run `prive policy apply`.",
+            file, POLICY_FILE
+        )
+        .into());
+    }
+    let scope = Path::new(note_dir).join(folder);
+    let wrapped = fs::read(scope.join(members::WRAPPED_KEY_FILE)).map_err(|_| {
+        format!(
+            "Refusing to save '{}': {} has no key for its readers yet; run `prive policy apply`.",
+            file, scope_name
+        )
+    })?;
+
+    let actual = members::load(&scope.to_string_lossy())?;
+    let unexpected: Vec<&str> = actual
+        .iter()
+        .filter(|member| !expected.iter().any(|e| e.recipient == member.recipient))
+        .map(|member| member.name.as_str())
+        .collect();
+    if !unexpected.is_empty() {
+        return Err(format!(
+            "Refusing to save '{}': the key of {} can be opened by {}, which {} does not allow; run `prive policy apply`.",
+            file,
+            scope_name,
+            unexpected.join(", "),
+            POLICY_FILE
+        )
+        .into());
+    }
+    if actual.len() != expected.len() || members::recipient_count(&wrapped)? != expected.len() {
+        return Err(format!(
+            "Refusing to save '{}': the key of {} is not wrapped for exactly the members {} allows; run `prive policy apply`.",
+            file, scope_name, POLICY_FILE
+        )
+        .into());
+    }
+
+    note_key(note_dir, &file)?.decrypt(&data).map_err(|_| {
+        format!(
+            "Refusing to save '{}': it is not encrypted with the key of {}; run `prive policy apply`.",
+            file, scope_name
+        )
+    })?;
+    Ok(())
+}
+
+/// Returns the folders that hold a key of their own, relative to the vault root
+fn scoped_folders(note_dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut folders = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(folder) = pending.pop() {
+        for entry in fs::read_dir(Path::new(note_dir).join(&folder))?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || !entry.path().is_dir() {
+                continue;
+            }
+            let child = format!("{}{}/", folder, name);
+            if entry.path().join(members::WRAPPED_KEY_FILE).exists() {
+                folders.push(child.clone());
+            }
+            pending.push(child);
+        }
+    }
+    Ok(folders)
+}
+
+/// Wraps folder keys for the members the policy names and re-encrypts notes to match
+///
+/// A folder key is replaced when a member loses access to it. Folders dropped
+/// from the policy go back to the vault key. Returns every file that changed;
+/// nothing is written until every note has been re-encrypted in memory.
+pub fn apply(note_dir: &str, notes: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let members = members::load(note_dir)?;
+    if members.is_empty() {
+        return Err(
+            "Per-folder access needs a shared vault; add members with `prive member add` first."
+                .into(),
+        );
+    }
+    // The policy itself is edited by hand, but the keys and member lists it
+    // is applied to must be the ones the vault last recorded
+    let access: Vec<String> = super::access_files(note_dir)?
+        .into_iter()
+        .filter(|file| file != POLICY_FILE)
+        .collect();
+    let access: Vec<&str> = access.iter().map(String::as_str).collect();
+    manifest::check_access(note_dir, &access)?;
+    let policy = load(note_dir, &members)?;
+    let old_folders = scoped_folders(note_dir)?;
+    let vault_key = key::vault_key(note_dir)?;
+
+    let mut new_keys = BTreeMap::new();
+    let mut rewrapped = Vec::new();
+    for rule in &policy.rules {
+        let expected = policy.recipients(rule, &members);
+        let scope = Path::new(note_dir).join(&rule.folder);
+        let current = members::load(&scope.to_string_lossy())?;
+        let has_key = old_folders.contains(&rule.folder);
+        // A key wrapped for more recipients than listed may be known to someone else
+        let extra_stanzas = has_key
+            && members::recipient_count(&fs::read(scope.join(members::WRAPPED_KEY_FILE))?)?
+                != current.len();
+        let lost_access = extra_stanzas
+            || current
+                .iter()
+                .any(|member| !expected.iter().any(|e| e.recipient == member.recipient));
+
+        let folder_key = if has_key && !lost_access {
+            folder_key(note_dir, &rule.folder)?
+        } else {
+            VaultKey::generate()
+        };
+        let same_readers = current.len() == expected.len() && !lost_access;
+        if !has_key || !same_readers {
+            rewrapped.push((rule.folder.clone(), expected, copy(&folder_key)?));
+        }
+        new_keys.insert(rule.folder.clone(), folder_key);
+    }
+
+    let mut reencrypted = Vec::new();
+    for file in notes {
+        let data = fs::read(Path::new(note_dir).join(file))?;
+        if !crypto::is_prive_format(&data) || crypto::key_mode(&data)? != KeyMode::Vault {
+            continue;
+        }
+        let old_key = match old_folders
+            .iter()
+            .filter(|folder| file.starts_with(folder.as_str()))
+            .max_by_key(|folder| folder.len())
+        {
+            Some(folder) => folder_key(note_dir, folder)?,
+            None => copy(&vault_key)?,
+        };
+        let new_key = match policy.rule_for(file) {
+            Some(rule) => copy(&new_keys[&rule.folder])?,
+            None => copy(&vault_key)?,
+        };
+        if old_key.as_bytes() == new_key.as_bytes() {
+            continue;
+        }
+        let plaintext = NoteKey::Vault(old_key)
+            .decrypt(&data)
+            .map_err(|e| format!("Failed to decrypt '{}': {}", file, e))?;
+        reencrypted.push((file.clone(), NoteKey::Vault(new_key).encrypt(&plaintext)?));
+    }
+
+    // New folder keys are saved before any note depends on them, and old
+    // ones are only removed once no note does
+    let mut changed = Vec::new();
+    for (folder, readers, folder_key) in &rewrapped {
+        let scope = Path::new(note_dir).join(folder);
+        fs::create_dir_all(&scope)?;
+        members::save(&scope.to_string_lossy(), readers)?;
+        members::wrap(&scope.to_string_lossy(), folder_key, readers)?;
+        changed.push(format!("{}{}", folder, members::MEMBERS_FILE));
+        changed.push(format!("{}{}", folder, members::WRAPPED_KEY_FILE));
+    }
+    for (file, data) in reencrypted {
+        workspace::write_atomic(&Path::new(note_dir).join(&file), &data)?;
+        changed.push(file);
+    }
+    for folder in &old_folders {
+        if policy.rules.iter().any(|rule| &rule.folder == folder) {
+            continue;
+        }
+        for file in [members::MEMBERS_FILE, members::WRAPPED_KEY_FILE] {
+            let _ = fs::remove_file(Path::new(note_dir).join(folder).join(file));
+            changed.push(format!("{}{}", folder, file));
+        }
+    }
+
+    if let Ok(mut unlocked) = UNLOCKED.lock() {
+        unlocked.clear();
+        for (folder, folder_key) in &new_keys {
+            unlocked.insert(folder.clone(), copy(folder_key)?);
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> Vec<Member> {
+        ["alice", "bob", "carol"]
+            .iter()
+            .map(|name| Member {
+                name: name.to_string(),
+                recipient: format!("age1{}", name),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_rules_and_skips_comments() {
+        let text = "# who reads what\n\nprod/ alice carol\n  prod/db/  alice  \n";
+        let policy = parse(text, &members()).unwrap();
+        assert_eq!(policy.rules.len(), 2);
+        assert_eq!(policy.rules[0].folder, "prod/");
+        assert_eq!(policy.rules[0].members, ["alice", "carol"]);
+        assert_eq!(policy.rules[1].folder, "prod/db/");
+        assert_eq!(policy.rules[1].members, ["alice"]);
+    }
+
+    #[test]
+    fn most_specific_folder_wins() {
+        let policy = parse("prod/ alice carol\nprod/db/ alice\n", &members()).unwrap();
+        let folder = |file| policy.rule_for(file).map(|rule| rule.folder.as_str());
+        assert_eq!(folder("prod/db/root.secured"), Some("prod/db/"));
+        assert_eq!(folder("prod/web.secured"), Some("prod/"));
+        assert_eq!(folder("production.secured"), None);
+        assert_eq!(folder("general.secured"), None);
+
+        let rule = policy.rule_for("prod/web.secured").unwrap();
+        let recipients = policy.recipients(rule, &members());
+        let names: Vec<_> = recipients.iter().map(|member| &member.name).collect();
+        assert_eq!(names, ["alice", "carol"]);
+    }
+
+    #[test]
+    fn invalid_folders_are_rejected() {
+        for folder in ["prod", "/", "/prod/", "../prod/", "prod/.git/", "prod//db/"] {
+            let text = format!("{} alice", folder);
+            assert!(parse(&text, &members()).is_err(), "{}", folder);
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(parse("prod/ alice\nprod/ bob\n", &members()).is_err());
+        assert!(parse("prod/\n", &members()).is_err());
+        assert!(parse("prod/ alice mallory\n", &members()).is_err());
+        assert!(parse("", &members()).unwrap().rules.is_empty());
+    }
+}
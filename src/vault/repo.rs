@@ -0,0 +1,907 @@
+use super::message;
+use super::signing::{self, Policy};
+use git2::build::CheckoutBuilder;
+use git2::{
+    Commit, Config, Cred, CredentialType, Direction, FetchOptions, Index, IndexAddOption,
+    IndexEntry, Oid, PushOptions, RemoteCallbacks, Repository, RepositoryInitOptions, Signature,
+    Sort,
+};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Name of the remote every vault clone pushes to and pulls from
+pub const REMOTE: &str = "origin";
+
+/// Branch used when neither the configuration nor the remote names one
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// Git config key in the vault repository that pins the remote branch to sync with
+pub const BRANCH_CONFIG: &str = "prive.branch";
+
+/// How many times credentials are offered before giving up
+const MAX_CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// Bits of an index entry's flags that hold its merge stage
+const INDEX_STAGE_MASK: u16 = 0x3000;
+
+/// Result of pulling the remote into the vault
+pub enum PullOutcome {
+    UpToDate,
+    FastForwarded,
+    Merged,
+}
+
+/// A file changed both on this device and on the remote since they last agreed
+pub struct Conflict {
+    /// Path of the file relative to the vault root
+    pub path: String,
+    /// Contents at the merge base, or None if both sides added the file
+    pub ancestor: Option<Vec<u8>>,
+    /// Contents on this device
+    pub ours: Vec<u8>,
+    /// Contents on the remote
+    pub theirs: Vec<u8>,
+}
+
+/// A commit that changed a vault file
+#[derive(Serialize)]
+pub struct Revision {
+    /// Abbreviated commit id
+    pub id: String,
+    /// Commit time in seconds since the Unix epoch
+    pub time: i64,
+    /// First line of the commit message
+    pub summary: String,
+}
+
+/// Git repository backing a vault
+pub struct Repo {
+    repo: Repository,
+    workdir: PathBuf,
+    /// Local branch checked out in the vault
+    local_branch: String,
+    /// Branch on the remote that the local branch is synchronised with
+    remote_branch: String,
+}
+
+impl Repo {
+    /// Opens the vault repository at the given path
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let repo = Repository::open(path)
+            .map_err(|e| format!("Failed to open the vault repository at {}: {}", path, e))?;
+        let workdir = repo
+            .workdir()
+            .ok_or("The vault repository has no working directory.")?
+            .to_path_buf();
+        let local_branch = head_branch(&repo)
+            .ok_or("The vault repository is not on a branch; check out the branch to sync.")?;
+        let remote_branch = configured_branch(&repo)
+            .or_else(|| upstream_branch(&repo, &local_branch))
+            .or_else(|| remote_head_branch(&repo))
+            .unwrap_or_else(|| local_branch.clone());
+        Ok(Repo {
+            repo,
+            workdir,
+            local_branch,
+            remote_branch,
+        })
+    }
+
+    /// Initialises a vault repository at the given path that syncs with a remote URL
+    ///
+    /// Existing repositories are pointed at the new remote. Any git URL works,
+    /// including SSH, HTTPS and local `file://` bare repositories. A new
+    /// repository tracks the given branch, or else the remote's default branch.
+    pub fn init(path: &str, url: &str, branch: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let existing = Repository::open(path).ok();
+        let created = existing.is_none();
+        let repo = match existing {
+            Some(repo) => repo,
+            None => Repository::init_opts(
+                path,
+                RepositoryInitOptions::new().initial_head(branch.unwrap_or(DEFAULT_BRANCH)),
+            )
+            .map_err(|e| format!("Failed to create a vault repository at {}: {}", path, e))?,
+        };
+
+        if repo.find_remote(REMOTE).is_ok() {
+            repo.remote_set_url(REMOTE, url)?;
+        } else {
+            repo.remote(REMOTE, url)?;
+        }
+
+        let mut config = repo.config()?;
+        if let Some(branch) = branch {
+            config.set_str(BRANCH_CONFIG, branch)?;
+        }
+        if created {
+            let remote_branch = match branch {
+                Some(branch) => branch.to_string(),
+                None => remote_default_branch(&repo, &config)?
+                    .unwrap_or_else(|| DEFAULT_BRANCH.to_string()),
+            };
+            let local_ref = format!("refs/heads/{}", remote_branch);
+            repo.set_head(&local_ref)?;
+            config.set_str(&format!("branch.{}.remote", remote_branch), REMOTE)?;
+            config.set_str(&format!("branch.{}.merge", remote_branch), &local_ref)?;
+        }
+
+        drop(repo);
+        Repo::open(path)
+    }
+
+    /// Returns the remote branch the vault is synchronised with
+    pub fn branch(&self) -> &str {
+        &self.remote_branch
+    }
+
+    /// Returns the URL of the vault's remote
+    pub fn remote_url(&self) -> Option<String> {
+        let remote = self.repo.find_remote(REMOTE).ok()?;
+        remote.url().map(str::to_string)
+    }
+
+    /// Registers a textconv command for a diff driver in the vault's git config
+    ///
+    /// Caching is turned off so decrypted text is never stored in git notes.
+    pub fn install_textconv(&self, driver: &str, command: &str) -> Result<(), Box<dyn Error>> {
+        let mut config = self.repo.config()?.open_level(git2::ConfigLevel::Local)?;
+        config.set_str(&format!("diff.{}.textconv", driver), command)?;
+        config.set_bool(&format!("diff.{}.cachetextconv", driver), false)?;
+        Ok(())
+    }
+
+    /// Returns the path of a vault file relative to the repository root
+    fn relative<'p>(&self, path: &'p str) -> &'p Path {
+        let path = Path::new(path);
+        path.strip_prefix(&self.workdir).unwrap_or(path)
+    }
+
+    /// Stages files for the next commit; files that no longer exist are staged as deleted
+    pub fn stage(&self, paths: &[&str]) -> Result<(), Box<dyn Error>> {
+        let mut index = self.repo.index()?;
+        for path in paths {
+            let relative = self.relative(path);
+            if self.workdir.join(relative).exists() {
+                index.add_all([relative], IndexAddOption::FORCE, None)?;
+            } else {
+                index.remove_path(relative)?;
+            }
+        }
+        index.write()?;
+        Ok(())
+    }
+
+    /// Returns the commit at HEAD, or None on a branch without commits
+    fn head_commit(&self) -> Result<Option<Commit<'_>>, Box<dyn Error>> {
+        match self.repo.head() {
+            Ok(head) => Ok(Some(head.peel_to_commit()?)),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => Ok(None),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns a string from the vault's git config, if set and not empty
+    fn config_string(&self, key: &str) -> Option<String> {
+        let value = self.repo.config().ok()?.get_string(key).ok()?;
+        Some(value).filter(|value| !value.is_empty())
+    }
+
+    /// Returns the editor command configured for the vault, if any
+    pub fn editor(&self) -> Option<String> {
+        self.config_string(crate::editor::EDITOR_CONFIG)
+    }
+
+    /// Renders the commit message for an action on notes from the configured template
+    pub fn message(&self, action: &str, note_ids: &[String]) -> String {
+        let template = match self.config_string(message::TEMPLATE_CONFIG) {
+            Some(template) => match message::validate(&template) {
+                Ok(_) => template,
+                Err(e) => {
+                    eprintln!("{} Using the default template.", e);
+                    message::DEFAULT_TEMPLATE.to_string()
+                }
+            },
+            None => message::DEFAULT_TEMPLATE.to_string(),
+        };
+        let device = self
+            .config_string(message::DEVICE_CONFIG)
+            .unwrap_or_else(message::device_name);
+        message::render(&template, action, note_ids, &device)
+    }
+
+    /// Returns the identity used for commits, falling back to a generic prive identity
+    fn signature(&self) -> Result<Signature<'static>, Box<dyn Error>> {
+        match self.repo.signature() {
+            Ok(signature) => Ok(signature),
+            Err(_) => Ok(Signature::now("prive", "prive@localhost")?),
+        }
+    }
+
+    /// Commits the staged changes, returning None if nothing changed
+    pub fn commit(&self, message: &str) -> Result<Option<Oid>, Box<dyn Error>> {
+        let mut index = self.repo.index()?;
+        let tree_id = index.write_tree()?;
+        let tree = self.repo.find_tree(tree_id)?;
+        let parent = self.head_commit()?;
+
+        if let Some(parent) = &parent {
+            if parent.tree_id() == tree_id {
+                return Ok(None);
+            }
+        }
+
+        let parents: Vec<&Commit> = parent.iter().collect();
+        Ok(Some(self.create_commit(message, &tree, &parents)?))
+    }
+
+    /// Returns the key this device signs vault commits with, if one is configured
+    pub fn signing_key(&self) -> Option<signing::SigningKey> {
+        signing::signing_key(&self.repo.config().ok()?)
+    }
+
+    /// Writes a commit on the local branch, signing it when a signing key is configured
+    fn create_commit(
+        &self,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&Commit],
+    ) -> Result<Oid, Box<dyn Error>> {
+        let signature = self.signature()?;
+        let Some(key) = self.signing_key() else {
+            return Ok(self.repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                tree,
+                parents,
+            )?);
+        };
+
+        let buffer = self
+            .repo
+            .commit_create_buffer(&signature, &signature, message, tree, parents)?;
+        let content = buffer
+            .as_str()
+            .ok_or("The commit content is not valid UTF-8.")?;
+        let armored = signing::sign(&key, content.as_bytes())?;
+        let oid = self.repo.commit_signed(content, &armored, None)?;
+
+        // Signed commits are written detached, so move the branch explicitly
+        let local_ref = format!("refs/heads/{}", self.local_branch);
+        self.repo.reference(&local_ref, oid, true, message)?;
+        Ok(oid)
+    }
+
+    /// Pushes the vault branch to the remote
+    pub fn push(&self) -> Result<(), Box<dyn Error>> {
+        let mut remote = self.repo.find_remote(REMOTE)?;
+        let rejection = RefCell::new(None);
+
+        let config = self.repo.config()?;
+        let mut callbacks = credential_callbacks(&config);
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                *rejection.borrow_mut() = Some(format!("{} was rejected: {}", refname, status));
+            }
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let refspec = format!(
+            "refs/heads/{}:refs/heads/{}",
+            self.local_branch, self.remote_branch
+        );
+        let pushed = remote.push(&[refspec.as_str()], Some(&mut options));
+        drop(options);
+
+        let reason = match (pushed, rejection.into_inner()) {
+            (Err(e), _) => e.message().to_string(),
+            (Ok(_), Some(status)) => status,
+            (Ok(_), None) => return Ok(()),
+        };
+        Err(self.push_error(&reason).into())
+    }
+
+    /// Turns the reason a push failed into an explanation of what to do next
+    fn push_error(&self, reason: &str) -> String {
+        let target = format!("{}/{}", REMOTE, self.remote_branch);
+        let lower = reason.to_lowercase();
+        if lower.contains("fast-forward")
+            || lower.contains("fastforward")
+            || lower.contains("fetch first")
+            || lower.contains("not present locally")
+        {
+            format!(
+                "The push to {} was rejected because the remote has changes this device does not have yet. \
+                 Your change is committed locally; pull the vault and push again.",
+                target
+            )
+        } else {
+            format!("The push to {} was rejected: {}", target, reason)
+        }
+    }
+
+    /// Fetches the remote branch, returning the commit it points at
+    ///
+    /// Returns None while the remote branch has no commits.
+    pub fn fetch(&self) -> Result<Option<Oid>, Box<dyn Error>> {
+        let mut remote = self.repo.find_remote(REMOTE)?;
+        let config = self.repo.config()?;
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(credential_callbacks(&config));
+        let refspec = format!(
+            "+refs/heads/{0}:refs/remotes/{1}/{0}",
+            self.remote_branch, REMOTE
+        );
+        remote
+            .fetch(&[refspec.as_str()], Some(&mut options), None)
+            .map_err(|e| format!("Failed to fetch from {}: {}", REMOTE, e.message()))?;
+
+        let remote_ref = format!("refs/remotes/{}/{}", REMOTE, self.remote_branch);
+        Ok(self.repo.refname_to_id(&remote_ref).ok())
+    }
+
+    /// Merges a fetched remote commit into the vault
+    ///
+    /// Files changed on both sides are handed to `resolve`, which returns the
+    /// merged contents to commit. Signatures are checked beforehand with
+    /// `verify_incoming`.
+    pub fn merge<F>(&self, remote_oid: Oid, resolve: F) -> Result<PullOutcome, Box<dyn Error>>
+    where
+        F: FnMut(&Conflict) -> Result<Vec<u8>, Box<dyn Error>>,
+    {
+        let remote_commit = self.repo.find_commit(remote_oid)?;
+        let annotated = self.repo.find_annotated_commit(remote_oid)?;
+        let (analysis, _) = self.repo.merge_analysis(&[&annotated])?;
+
+        if analysis.is_up_to_date() {
+            return Ok(PullOutcome::UpToDate);
+        }
+
+        let local_ref = format!("refs/heads/{}", self.local_branch);
+        if analysis.is_fast_forward() || analysis.is_unborn() {
+            self.repo.checkout_tree(
+                remote_commit.as_object(),
+                Some(CheckoutBuilder::new().safe()),
+            )?;
+            self.repo
+                .reference(&local_ref, remote_oid, true, "prive: fast-forward")?;
+            self.repo.set_head(&local_ref)?;
+            return Ok(PullOutcome::FastForwarded);
+        }
+
+        let local_commit = self
+            .head_commit()?
+            .ok_or("The vault has no local commits to merge into.")?;
+        let mut index = self
+            .repo
+            .merge_commits(&local_commit, &remote_commit, None)?;
+        if index.has_conflicts() {
+            self.resolve_conflicts(&mut index, resolve)?;
+        }
+
+        // Update the working tree while HEAD still describes what is on disk
+        let tree_id = index.write_tree_to(&self.repo)?;
+        let tree = self.repo.find_tree(tree_id)?;
+        self.repo
+            .checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().safe()))?;
+
+        self.create_commit(
+            &self.message("merge", &[]),
+            &tree,
+            &[&local_commit, &remote_commit],
+        )?;
+        Ok(PullOutcome::Merged)
+    }
+
+    /// Checks that every commit about to be pulled is signed by a trusted device
+    ///
+    /// The trusted signers are read from the local HEAD, so a pulled commit
+    /// cannot vouch for itself by adding its own key; a new clone falls back
+    /// to the list on the remote. Changes to the trusted signers themselves
+    /// must be signed by a device already trusted, whatever the policy.
+    pub fn verify_incoming(&self, remote_oid: Oid) -> Result<(), Box<dyn Error>> {
+        self.verify_trust_changes(remote_oid)?;
+        let policy = Policy::from_config(self.config_string(signing::VERIFY_CONFIG).as_deref());
+        if policy == Policy::Off {
+            return Ok(());
+        }
+
+        let trusted = match self.trusted_signers(remote_oid) {
+            Some(trusted) => trusted,
+            None if policy == Policy::Refuse => {
+                return Err(format!(
+                    "The vault has no {} file, so pulled commits cannot be verified.",
+                    signing::TRUSTED_SIGNERS_FILE
+                )
+                .into())
+            }
+            None => return Ok(()),
+        };
+
+        let mut problems = Vec::new();
+        for oid in self.incoming_commits(remote_oid)? {
+            if let Some(problem) = self.signature_problem(&trusted, oid) {
+                problems.push(problem);
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if policy == Policy::Refuse {
+            return Err(format!(
+                "Refusing to pull unverified commits:\n  {}",
+                problems.join("\n  ")
+            )
+            .into());
+        }
+        for problem in problems {
+            eprintln!("Warning: pulled commit {}.", problem);
+        }
+        Ok(())
+    }
+
+    /// Counts local commits that the remote branch does not have yet
+    ///
+    /// The remote-tracking branch records what the remote had at the last
+    /// successful fetch or push, so the count survives restarts and works offline.
+    pub fn unsynced_commits(&self) -> Result<usize, Box<dyn Error>> {
+        let head = match self.head_commit()? {
+            Some(head) => head.id(),
+            None => return Ok(0),
+        };
+        let remote_ref = format!("refs/remotes/{}/{}", REMOTE, self.remote_branch);
+        match self.repo.refname_to_id(&remote_ref) {
+            Ok(remote) => Ok(self.repo.graph_ahead_behind(head, remote)?.0),
+            Err(_) => {
+                // Nothing has reached the remote yet
+                let mut revwalk = self.repo.revwalk()?;
+                revwalk.push(head)?;
+                Ok(revwalk.count())
+            }
+        }
+    }
+
+    /// Lists the commits that changed a vault file, newest first
+    pub fn history(&self, path: &str) -> Result<Vec<Revision>, Box<dyn Error>> {
+        let path = self.relative(path);
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        if self.head_commit()?.is_none() {
+            return Ok(Vec::new());
+        }
+        revwalk.push_head()?;
+
+        let mut revisions = Vec::new();
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            let blob = blob_id(&commit, path);
+
+            // A commit touches the file when no parent already had the same contents
+            let unchanged = commit
+                .parents()
+                .any(|parent| blob_id(&parent, path) == blob);
+            if (blob.is_none() && commit.parent_count() == 0) || unchanged {
+                continue;
+            }
+
+            revisions.push(Revision {
+                id: commit
+                    .as_object()
+                    .short_id()?
+                    .as_str()
+                    .unwrap_or("")
+                    .to_string(),
+                time: commit.time().seconds(),
+                summary: commit.summary().unwrap_or("").to_string(),
+            });
+        }
+        Ok(revisions)
+    }
+
+    /// Returns true if a fetched commit is already part of the local branch
+    pub fn contains(&self, oid: Oid) -> Result<bool, Box<dyn Error>> {
+        match self.head_commit()? {
+            Some(head) => Ok(head.id() == oid || self.repo.graph_descendant_of(head.id(), oid)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Checks that fetched commits changing any of the given files are signed by a trusted device
+    ///
+    /// Unlike other commits, these are refused even when unverified commits
+    /// only warn. Vaults without trusted signers, or with verification turned
+    /// off, are not checked.
+    pub fn verify_signed_changes(&self, rev: &str, paths: &[&str]) -> Result<(), Box<dyn Error>> {
+        let policy = Policy::from_config(self.config_string(signing::VERIFY_CONFIG).as_deref());
+        let remote_oid = self.repo.revparse_single(rev)?.peel_to_commit()?.id();
+        let trusted = match self.trusted_signers(remote_oid) {
+            Some(trusted) if policy != Policy::Off && !trusted.is_empty() => trusted,
+            _ => return Ok(()),
+        };
+
+        let mut problems = Vec::new();
+        for oid in self.incoming_commits(remote_oid)? {
+            if !self.changes_any(oid, paths)? {
+                continue;
+            }
+            if let Some(problem) = self.signature_problem(&trusted, oid) {
+                problems.push(problem);
+            }
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "Refusing to pull unverified changes to {}:\n  {}",
+            paths.join(", "),
+            problems.join("\n  ")
+        )
+        .into())
+    }
+
+    /// Returns true if a fetched commit is signed by a trusted device
+    ///
+    /// Always false when the vault has no trusted signers or verification is off.
+    pub fn is_trusted_commit(&self, oid: Oid) -> bool {
+        let policy = Policy::from_config(self.config_string(signing::VERIFY_CONFIG).as_deref());
+        match self.trusted_signers(oid) {
+            Some(trusted) if policy != Policy::Off && !trusted.is_empty() => {
+                self.signature_problem(&trusted, oid).is_none()
+            }
+            _ => false,
+        }
+    }
+
+    /// Refuses fetched commits that change the trusted signers without a signature from one of them
+    ///
+    /// A vault that trusts nobody yet has no one to vouch for the first list;
+    /// the manifest keeps devices outside the vault from adding it.
+    fn verify_trust_changes(&self, remote_oid: Oid) -> Result<(), Box<dyn Error>> {
+        let trusted = match self.file_at(signing::TRUSTED_SIGNERS_FILE, "HEAD") {
+            Ok(trusted) => signing::parse_trusted(&String::from_utf8_lossy(&trusted)),
+            Err(_) => return Ok(()),
+        };
+        if trusted.is_empty() {
+            return Ok(());
+        }
+
+        let mut problems = Vec::new();
+        for oid in self.incoming_commits(remote_oid)? {
+            if !self.changes_any(oid, &[signing::TRUSTED_SIGNERS_FILE])? {
+                continue;
+            }
+            if let Some(problem) = self.signature_problem(&trusted, oid) {
+                problems.push(problem);
+            }
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "Refusing to pull unverified changes to {}:\n  {}",
+            signing::TRUSTED_SIGNERS_FILE,
+            problems.join("\n  ")
+        )
+        .into())
+    }
+
+    /// Returns the trusted signers from the local HEAD, or from a fetched commit on a new clone
+    fn trusted_signers(&self, remote_oid: Oid) -> Option<Vec<signing::TrustedSigner>> {
+        let trusted = self
+            .file_at(signing::TRUSTED_SIGNERS_FILE, "HEAD")
+            .or_else(|_| self.file_at(signing::TRUSTED_SIGNERS_FILE, &remote_oid.to_string()))
+            .ok()?;
+        Some(signing::parse_trusted(&String::from_utf8_lossy(&trusted)))
+    }
+
+    /// Lists the fetched commits the local branch does not have yet, oldest first
+    pub fn incoming_commits(&self, remote_oid: Oid) -> Result<Vec<Oid>, Box<dyn Error>> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(remote_oid)?;
+        if let Some(head) = self.head_commit()? {
+            revwalk.hide(head.id())?;
+        }
+        Ok(revwalk.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns whether a commit introduced a version of any of the files that none of its parents had
+    ///
+    /// A merge that takes one side's file unchanged does not count; the
+    /// commit that side made it in does.
+    pub fn changes_any(&self, oid: Oid, paths: &[&str]) -> Result<bool, Box<dyn Error>> {
+        let commit = self.repo.find_commit(oid)?;
+        let blob = |commit: &Commit, path: &str| {
+            commit
+                .tree()
+                .ok()
+                .and_then(|tree| tree.get_path(Path::new(path)).ok())
+                .map(|entry| entry.id())
+        };
+        Ok(paths.iter().any(|path| {
+            let ours = blob(&commit, path);
+            commit.parents().all(|parent| blob(&parent, path) != ours)
+        }))
+    }
+
+    /// Describes why a commit's signature does not come from a trusted device, if it does not
+    fn signature_problem(&self, trusted: &[signing::TrustedSigner], oid: Oid) -> Option<String> {
+        let short = oid.to_string()[..7].to_string();
+        match self.repo.extract_signature(&oid, None) {
+            Ok((signature, content)) => {
+                let signature = String::from_utf8_lossy(&signature).into_owned();
+                match signing::verify(trusted, &signature, &content) {
+                    Ok(Some(_)) => None,
+                    Ok(None) => Some(format!("{} is signed by an unknown key", short)),
+                    Err(e) => Some(format!("{} has an invalid signature: {}", short, e)),
+                }
+            }
+            Err(_) => Some(format!("{} is unsigned", short)),
+        }
+    }
+
+    /// Returns the last commit both the local branch and a fetched commit descend from
+    pub fn merge_base(&self, rev: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(head) = self.head_commit()? else {
+            return Ok(None);
+        };
+        let oid = self.repo.revparse_single(rev)?.peel_to_commit()?.id();
+        match self.repo.merge_base(head.id(), oid) {
+            Ok(base) => Ok(Some(base.to_string())),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lists the paths of all files in the vault at a revision
+    pub fn files_at(&self, rev: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let tree = self
+            .repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_tree())
+            .map_err(|_| format!("Unknown revision '{}'.", rev))?;
+        let mut files = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                if let Some(name) = entry.name() {
+                    files.push(format!("{}{}", dir, name));
+                }
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+        Ok(files)
+    }
+
+    /// Returns the path of a device-local state file kept inside the repository's git directory
+    pub fn state_path(&self, name: &str) -> PathBuf {
+        self.repo.path().join(name)
+    }
+
+    /// Reads a vault file as it was at a revision such as a commit id, `HEAD~2` or a branch
+    pub fn file_at(&self, path: &str, rev: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = self.relative(path);
+        let commit = self
+            .repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|_| format!("Unknown revision '{}'.", rev))?;
+        let entry = commit
+            .tree()?
+            .get_path(path)
+            .map_err(|_| format!("{} did not exist at revision '{}'.", path.display(), rev))?;
+        let blob = self.repo.find_blob(entry.id())?;
+        Ok(blob.content().to_vec())
+    }
+
+    /// Replaces every conflict in a merge index with the resolved contents
+    fn resolve_conflicts<F>(&self, index: &mut Index, mut resolve: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&Conflict) -> Result<Vec<u8>, Box<dyn Error>>,
+    {
+        let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+        for conflict in conflicts {
+            let mut entry = match (conflict.our, conflict.their) {
+                (Some(ours), Some(theirs)) => {
+                    let path = String::from_utf8_lossy(&ours.path).into_owned();
+                    let ancestor = match &conflict.ancestor {
+                        Some(ancestor) => Some(self.blob_content(ancestor)?),
+                        None => None,
+                    };
+                    let merged = resolve(&Conflict {
+                        path,
+                        ancestor,
+                        ours: self.blob_content(&ours)?,
+                        theirs: self.blob_content(&theirs)?,
+                    })?;
+                    IndexEntry {
+                        id: self.repo.blob(&merged)?,
+                        file_size: merged.len() as u32,
+                        ..ours
+                    }
+                }
+                // A note edited on one side and deleted on the other keeps the edit
+                (Some(kept), None) | (None, Some(kept)) => kept,
+                (None, None) => continue,
+            };
+
+            // Drop the base, ours and theirs stages before adding the resolution
+            let path = PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+            for stage in 1..=3 {
+                let _ = index.remove(&path, stage);
+            }
+            entry.flags &= !INDEX_STAGE_MASK;
+            index.add(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the blob an index entry points at
+    fn blob_content(&self, entry: &IndexEntry) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.repo.find_blob(entry.id)?.content().to_vec())
+    }
+}
+
+/// Returns the id of a file's blob in a commit, or None if the file is absent
+fn blob_id(commit: &Commit, path: &Path) -> Option<Oid> {
+    let tree = commit.tree().ok()?;
+    let entry = tree.get_path(path).ok()?;
+    Some(entry.id())
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time
+pub fn format_time(seconds: i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let minutes = seconds.rem_euclid(86_400) / 60;
+
+    // Convert days since the epoch to a civil date (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// Returns the branch HEAD points at, even if it has no commits yet
+fn head_branch(repo: &Repository) -> Option<String> {
+    let head = repo.find_reference("HEAD").ok()?;
+    let target = head.symbolic_target()?;
+    target.strip_prefix("refs/heads/").map(str::to_string)
+}
+
+/// Returns the remote branch pinned in the vault's git config
+fn configured_branch(repo: &Repository) -> Option<String> {
+    let branch = repo.config().ok()?.get_string(BRANCH_CONFIG).ok()?;
+    Some(branch).filter(|branch| !branch.is_empty())
+}
+
+/// Returns the remote branch a local branch tracks
+fn upstream_branch(repo: &Repository, local_branch: &str) -> Option<String> {
+    let config = repo.config().ok()?;
+    let merge = config
+        .get_string(&format!("branch.{}.merge", local_branch))
+        .ok()?;
+    merge.strip_prefix("refs/heads/").map(str::to_string)
+}
+
+/// Returns the remote's default branch as recorded by the last clone or fetch
+fn remote_head_branch(repo: &Repository) -> Option<String> {
+    let head = repo
+        .find_reference(&format!("refs/remotes/{}/HEAD", REMOTE))
+        .ok()?;
+    let target = head.symbolic_target()?;
+    target
+        .strip_prefix(&format!("refs/remotes/{}/", REMOTE))
+        .map(str::to_string)
+}
+
+/// Asks the remote which branch it checks out by default; None for an empty remote
+fn remote_default_branch(
+    repo: &Repository,
+    config: &Config,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let mut remote = repo.find_remote(REMOTE)?;
+    let connection = remote
+        .connect_auth(Direction::Fetch, Some(credential_callbacks(config)), None)
+        .map_err(|e| format!("Failed to connect to {}: {}", REMOTE, e.message()))?;
+    let branch = match connection.default_branch() {
+        Ok(name) => name
+            .as_str()
+            .and_then(|name| name.strip_prefix("refs/heads/"))
+            .map(str::to_string),
+        Err(_) => None,
+    };
+    Ok(branch)
+}
+
+/// Builds callbacks that answer credential requests from SSH keys or git's credential helpers
+///
+/// SSH remotes are offered the SSH agent's keys first, then each unencrypted
+/// key file from `ssh_key_files` in turn.
+fn credential_callbacks(config: &Config) -> RemoteCallbacks<'_> {
+    let key_files = ssh_key_files(config);
+    let max_attempts = MAX_CREDENTIAL_ATTEMPTS + key_files.len() as u32;
+    let attempts = Cell::new(0);
+    let tried_agent = Cell::new(false);
+    let next_key_file = Cell::new(0);
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() > max_attempts {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let username = username.unwrap_or("git");
+            if !tried_agent.replace(true) && env::var_os("SSH_AUTH_SOCK").is_some() {
+                return Cred::ssh_key_from_agent(username);
+            }
+            let Some(key_file) = key_files.get(next_key_file.get()) else {
+                return Err(git2::Error::from_str(
+                    "no SSH key was accepted; add one to the SSH agent",
+                ));
+            };
+            next_key_file.set(next_key_file.get() + 1);
+            return Cred::ssh_key(username, None, key_file, None);
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            return Cred::credential_helper(config, url, username);
+        }
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username.unwrap_or("git"));
+        }
+        Cred::default()
+    });
+    callbacks
+}
+
+/// Returns the SSH private keys to offer when the agent has none that work
+///
+/// Keys given with `-i` in `core.sshCommand` come first, then the default
+/// `~/.ssh` keys that exist.
+fn ssh_key_files(config: &Config) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(command) = config.get_string("core.sshCommand") {
+        let mut words = command.split_whitespace();
+        while let Some(word) = words.next() {
+            let file = match word.strip_prefix("-i") {
+                Some("") => words.next(),
+                Some(file) => Some(file),
+                None => None,
+            };
+            files.extend(file.map(signing::expand_home));
+        }
+    }
+    if let Some(home) = env::var_os("HOME") {
+        let ssh_dir = Path::new(&home).join(".ssh");
+        files.extend(
+            ["id_ed25519", "id_ecdsa", "id_rsa"]
+                .iter()
+                .map(|name| ssh_dir.join(name)),
+        );
+    }
+    files.retain(|file| file.is_file());
+    files
+}
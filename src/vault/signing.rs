@@ -0,0 +1,331 @@
+use crate::workspace;
+use git2::Config;
+use serde::Serialize;
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+/// File in the vault listing the device keys whose commits are trusted
+pub const TRUSTED_SIGNERS_FILE: &str = "trusted-signers";
+
+/// Git config key in the vault repository choosing how unverified commits are handled
+pub const VERIFY_CONFIG: &str = "prive.verifySignatures";
+
+/// Namespace SSH signatures are made in, matching git's own SSH signing
+const SSH_NAMESPACE: &str = "git";
+
+/// Key type recorded in the trusted signers file for GPG fingerprints
+const GPG_KEY_TYPE: &str = "gpg";
+
+/// What to do with pulled commits that are unsigned or signed by an unknown key
+#[derive(PartialEq)]
+pub enum Policy {
+    Off,
+    Warn,
+    Refuse,
+}
+
+impl Policy {
+    /// Parses the configured policy, warning by default
+    pub fn from_config(value: Option<&str>) -> Policy {
+        match value {
+            Some("off") => Policy::Off,
+            Some("refuse") => Policy::Refuse,
+            _ => Policy::Warn,
+        }
+    }
+}
+
+/// Key this device signs its commits with, taken from git's own signing config
+pub enum SigningKey {
+    /// Path to an SSH key, or a literal public key held by the SSH agent
+    Ssh(String),
+    /// GPG key id or fingerprint
+    Gpg(String),
+}
+
+/// Returns the signing key configured through `user.signingkey` and `gpg.format`
+pub fn signing_key(config: &Config) -> Option<SigningKey> {
+    let key = config.get_string("user.signingkey").ok()?;
+    if key.is_empty() {
+        return None;
+    }
+    match config.get_string("gpg.format").ok().as_deref() {
+        Some("ssh") => Some(SigningKey::Ssh(key)),
+        _ => Some(SigningKey::Gpg(key)),
+    }
+}
+
+/// A device key allowed to sign vault commits
+#[derive(Serialize)]
+pub struct TrustedSigner {
+    /// Name of the device the key belongs to
+    pub device: String,
+    /// SSH key type such as `ssh-ed25519`, or `gpg`
+    pub key_type: String,
+    /// Base64 SSH public key or GPG fingerprint
+    pub key: String,
+}
+
+impl TrustedSigner {
+    /// Formats the signer as a line of the trusted signers file
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", self.device, self.key_type, self.key)
+    }
+}
+
+/// Parses the trusted signers file, skipping comments and malformed lines
+pub fn parse_trusted(text: &str) -> Vec<TrustedSigner> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(TrustedSigner {
+                device: fields.next()?.to_string(),
+                key_type: fields.next()?.to_string(),
+                key: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Temporary file for handing keys and signatures to ssh-keygen and gpg
+struct ScratchFile {
+    path: PathBuf,
+}
+
+impl ScratchFile {
+    fn create(name: &str, contents: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let dir = workspace::runtime_dir().join("sign");
+        workspace::create_private_dir(&dir)?;
+        let path = dir.join(format!("{}-{}", process::id(), name));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        let scratch = ScratchFile { path };
+        file.write_all(contents)?;
+        Ok(scratch)
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Runs a signing tool with data on stdin and returns what it printed
+fn run_with_input(command: &mut Command, input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or("Failed to open the signing tool's stdin.")?
+        .write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_string()
+            .into());
+    }
+    Ok(output.stdout)
+}
+
+/// Expands a leading `~/` in a configured key path
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Returns the literal public key from an SSH `user.signingkey` value, if it is one
+fn literal_ssh_key(key: &str) -> Option<&str> {
+    let key = key.strip_prefix("key::").unwrap_or(key);
+    Some(key).filter(|key| key.starts_with("ssh-") || key.starts_with("ecdsa-"))
+}
+
+/// Signs commit content, returning an armored signature for the commit header
+pub fn sign(key: &SigningKey, content: &[u8]) -> Result<String, Box<dyn Error>> {
+    let signature = match key {
+        SigningKey::Ssh(key) => {
+            // Literal public keys are signed by the matching key in the SSH agent
+            let literal = match literal_ssh_key(key) {
+                Some(public_key) => Some(ScratchFile::create(
+                    "signing-key.pub",
+                    public_key.as_bytes(),
+                )?),
+                None => None,
+            };
+            let key_path = match &literal {
+                Some(file) => file.path.clone(),
+                None => expand_home(key),
+            };
+            run_with_input(
+                Command::new("ssh-keygen")
+                    .args(["-Y", "sign", "-n", SSH_NAMESPACE, "-f"])
+                    .arg(&key_path),
+                content,
+            )
+        }
+        SigningKey::Gpg(key) => run_with_input(
+            Command::new("gpg").args(["--batch", "--armor", "--detach-sign", "--local-user", key]),
+            content,
+        ),
+    }
+    .map_err(|e| format!("Failed to sign the commit: {}", e))?;
+    Ok(String::from_utf8(signature)?)
+}
+
+/// Verifies a commit signature, returning the trusted device that made it
+///
+/// Returns None when the signature is valid but the key is not trusted.
+pub fn verify(
+    trusted: &[TrustedSigner],
+    signature: &str,
+    content: &[u8],
+) -> Result<Option<String>, Box<dyn Error>> {
+    if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+        verify_ssh(trusted, signature, content)
+    } else {
+        verify_gpg(trusted, signature, content)
+    }
+}
+
+/// Verifies an SSH signature against the trusted SSH keys
+fn verify_ssh(
+    trusted: &[TrustedSigner],
+    signature: &str,
+    content: &[u8],
+) -> Result<Option<String>, Box<dyn Error>> {
+    let allowed: String = trusted
+        .iter()
+        .filter(|signer| signer.key_type != GPG_KEY_TYPE)
+        .map(|signer| format!("{}\n", signer.to_line()))
+        .collect();
+    let allowed = ScratchFile::create("allowed-signers", allowed.as_bytes())?;
+    let signature = ScratchFile::create("signature", signature.as_bytes())?;
+
+    let principals = Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-s"])
+        .arg(&signature.path)
+        .arg("-f")
+        .arg(&allowed.path)
+        .stderr(Stdio::null())
+        .output()?;
+    let principals = String::from_utf8_lossy(&principals.stdout).into_owned();
+    let Some(device) = principals.lines().next().map(str::to_string) else {
+        return Ok(None);
+    };
+
+    run_with_input(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", SSH_NAMESPACE, "-I", &device, "-s"])
+            .arg(&signature.path)
+            .arg("-f")
+            .arg(&allowed.path),
+        content,
+    )
+    .map_err(|e| format!("bad SSH signature: {}", e))?;
+    Ok(Some(device))
+}
+
+/// Verifies a GPG signature and matches its key against the trusted fingerprints
+fn verify_gpg(
+    trusted: &[TrustedSigner],
+    signature: &str,
+    content: &[u8],
+) -> Result<Option<String>, Box<dyn Error>> {
+    let signature = ScratchFile::create("signature.asc", signature.as_bytes())?;
+    let status = run_with_input(
+        Command::new("gpg")
+            .args(["--batch", "--status-fd", "1", "--verify"])
+            .arg(&signature.path)
+            .arg("-"),
+        content,
+    )
+    .map_err(|e| format!("bad GPG signature: {}", e))?;
+
+    // VALIDSIG lists the signing key's fingerprint and, last, its primary key's
+    let status = String::from_utf8_lossy(&status).into_owned();
+    let fingerprints: Vec<&str> = status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|fields| {
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            [fields.first().copied(), fields.last().copied()]
+        })
+        .flatten()
+        .collect();
+    Ok(trusted
+        .iter()
+        .filter(|signer| signer.key_type == GPG_KEY_TYPE)
+        .find(|signer| {
+            fingerprints
+                .iter()
+                .any(|fingerprint| fingerprint.eq_ignore_ascii_case(&signer.key))
+        })
+        .map(|signer| signer.device.clone()))
+}
+
+/// Returns the trusted signer entry for this device's signing key
+pub fn signer_for(device: &str, key: &SigningKey) -> Result<TrustedSigner, Box<dyn Error>> {
+    match key {
+        SigningKey::Ssh(key) => {
+            let public_key = match literal_ssh_key(key) {
+                Some(public_key) => public_key.to_string(),
+                None => {
+                    let path = expand_home(key);
+                    let path = if path.extension().is_some_and(|ext| ext == "pub") {
+                        path
+                    } else {
+                        PathBuf::from(format!("{}.pub", path.display()))
+                    };
+                    fs::read_to_string(&path)
+                        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+                }
+            };
+            let mut fields = public_key.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(key_type), Some(key)) => Ok(TrustedSigner {
+                    device: device.to_string(),
+                    key_type: key_type.to_string(),
+                    key: key.to_string(),
+                }),
+                _ => Err("The SSH public key is malformed.".into()),
+            }
+        }
+        SigningKey::Gpg(key) => Ok(TrustedSigner {
+            device: device.to_string(),
+            key_type: GPG_KEY_TYPE.to_string(),
+            key: gpg_fingerprint(key)?,
+        }),
+    }
+}
+
+/// Looks up the full fingerprint of a GPG key id
+fn gpg_fingerprint(key: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("gpg")
+        .args(["--batch", "--with-colons", "--fingerprint", key])
+        .stderr(Stdio::null())
+        .output()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("fpr:"))
+        .and_then(|fields| fields.split(':').find(|field| !field.is_empty()))
+        .map(str::to_string)
+        .ok_or_else(|| format!("No GPG key found for '{}'.", key).into())
+}
@@ -0,0 +1,233 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::{env, process};
+use zeroize::Zeroizing;
+
+/// Returns the private per-user runtime directory, preferring memory-backed locations
+pub fn runtime_dir() -> PathBuf {
+    let uid = unsafe { libc::getuid() };
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("prive"),
+        _ if Path::new("/dev/shm").is_dir() => PathBuf::from(format!("/dev/shm/prive-{}", uid)),
+        _ => env::temp_dir().join(format!("prive-{}", uid)),
+    }
+}
+
+/// Creates a directory readable only by the current user
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+/// Returns the directory holding decrypted working copies
+pub fn edit_dir() -> PathBuf {
+    runtime_dir().join("edit")
+}
+
+/// Overwrites a file with zeros before unlinking it
+pub fn wipe_file(path: &Path) -> io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(0))?;
+    let zeros = [0u8; 4096];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+/// Replaces a file's contents so a crash leaves either the old or the new contents
+///
+/// The data is written to a hidden temporary file beside the target, flushed
+/// to disk and renamed over it.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let temp_path = dir.join(format!(".{}.tmp-{}", name.to_string_lossy(), process::id()));
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written?;
+    // Make the rename itself durable
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Decrypted copy of a note that lives outside the vault for the duration of an edit
+///
+/// The copy is wiped when dropped, so it never outlives the editing session.
+pub struct WorkingCopy {
+    path: PathBuf,
+}
+
+impl WorkingCopy {
+    /// Writes plaintext to a new 0600 file in the private edit directory
+    pub fn create(note_name: &str, plaintext: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let dir = edit_dir();
+        create_private_dir(&dir)?;
+
+        // Keep the note's own name last so editors still pick up its file type
+        let path = dir.join(format!("{}-{}", process::id(), encode_note_name(note_name)));
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let copy = WorkingCopy { path };
+        file.write_all(plaintext)?;
+        file.sync_all()?;
+        Ok(copy)
+    }
+
+    /// Returns the path handed to the editor
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the current contents of the working copy
+    pub fn read(&self) -> io::Result<Zeroizing<Vec<u8>>> {
+        fs::read(&self.path).map(Zeroizing::new)
+    }
+}
+
+impl Drop for WorkingCopy {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            if let Err(e) = wipe_file(&self.path) {
+                eprintln!("Failed to wipe working copy {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Encodes a note's path relative to the vault as a single file name
+///
+/// Folder separators become `%2F`, so the note's folder survives a crash.
+fn encode_note_name(note_name: &str) -> String {
+    note_name.replace('%', "%25").replace('/', "%2F")
+}
+
+/// Decodes a working copy's note name, keeping only the file name of paths leaving the vault
+fn decode_note_name(encoded: &str) -> String {
+    let note_name = encoded.replace("%2F", "/").replace("%25", "%");
+    let inside_vault = !note_name.is_empty()
+        && Path::new(&note_name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if inside_vault {
+        return note_name;
+    }
+    Path::new(&note_name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "note".to_string())
+}
+
+/// Decrypted note left behind by an interrupted editing session
+pub struct Orphan {
+    /// Location of the leftover plaintext
+    pub path: PathBuf,
+    /// Path of the note it belongs to relative to the vault, without the `.secured` suffix
+    pub note_name: String,
+}
+
+/// Returns true if a process with this id is still running
+fn process_alive(pid: i32) -> bool {
+    let alive = unsafe { libc::kill(pid, 0) == 0 };
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Finds plaintext working copies and notes left behind by crashed sessions
+///
+/// Working copies still owned by a running prive process are not orphans;
+/// inside the vault, any plain file sitting beside its `.secured` counterpart is.
+pub fn find_orphans(note_dir: &str) -> Vec<Orphan> {
+    let mut orphans = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(edit_dir()) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some((pid, note_name)) = file_name.split_once('-') else {
+                continue;
+            };
+            match pid.parse::<i32>() {
+                Ok(pid) if process_alive(pid) => continue,
+                Ok(_) => orphans.push(Orphan {
+                    path: entry.path(),
+                    note_name: decode_note_name(note_name),
+                }),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(note_dir) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let secured = Path::new(note_dir).join(format!("{}.secured", file_name));
+            if entry.path().is_file() && secured.exists() {
+                orphans.push(Orphan {
+                    path: entry.path(),
+                    note_name: file_name,
+                });
+            }
+        }
+    }
+
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+    orphans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_names_round_trip() {
+        for note_name in [
+            "general",
+            "prod/db/root",
+            "50%/off",
+            "a%2Fb",
+            "%25",
+            "x%2/F",
+        ] {
+            let encoded = encode_note_name(note_name);
+            assert!(!encoded.contains('/'), "{}", encoded);
+            assert_eq!(decode_note_name(&encoded), note_name);
+        }
+    }
+
+    #[test]
+    fn decoded_names_stay_inside_the_vault() {
+        assert_eq!(decode_note_name("..%2F..%2Fetc%2Fpasswd"), "passwd");
+        assert_eq!(decode_note_name("%2Fetc%2Fshadow"), "shadow");
+        assert_eq!(decode_note_name("prod%2F..%2F..%2Fkeys"), "keys");
+        assert_eq!(decode_note_name(".."), "note");
+        assert_eq!(decode_note_name(""), "note");
+    }
+}